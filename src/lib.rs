@@ -5,14 +5,17 @@ use nix::sys::socket::SockaddrLike;
 use nix::sys::socket::SockaddrStorage;
 use nix::sys::stat::fstat;
 use nix::sys::stat::SFlag;
-use std::cell::RefCell;
+use regex::Regex;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::convert::TryInto;
 use std::fmt;
 use std::io::BufRead;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::os::unix::io::RawFd;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 
 #[derive(Debug, PartialEq, PartialOrd, Hash, Eq, Clone)]
 pub enum Addr {
@@ -26,16 +29,136 @@ pub struct Ip {
     pub cidr: u32,
 }
 
+#[derive(Clone)]
 pub struct NetRow {
     pub row: HashMap<String, String>,
 }
 
+// a csv row given as a start and end address rather than a network
+#[derive(Clone)]
+pub struct NetRange {
+    pub start: Addr,
+    pub end: Addr,
+    pub row: NetRow,
+}
+
+#[derive(Default)]
+pub struct NetRows {
+    pub networks: HashMap<Ip, NetRow>,
+    pub ranges: Vec<NetRange>,
+    // the highest end address of ranges up to each position, see sort_ranges
+    range_ends: Vec<Addr>,
+    // rows looked up in a compiled index rather than held here
+    index: Option<CsvIndex>,
+}
+
+// a csv file that an index was compiled from
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexSource {
+    pub path: String,
+    pub modified: std::time::Duration,
+    pub size: u64,
+    pub sha1: [u8; 20],
+}
+
+// --csv rows written by compile_index, mapped rather than read, rows are
+// decoded when they are first matched
+pub struct CsvIndex {
+    map: memmap2::Mmap,
+    pub key: String,
+    pub sources: Vec<IndexSource>,
+    pub fields: Vec<String>,
+    names: Vec<String>,
+    row_count: usize,
+    row_offsets: usize,
+    row_data: usize,
+    value_count: usize,
+    value_offsets: usize,
+    value_data: usize,
+    roots: [u32; 2],
+    node_count: usize,
+    nodes: usize,
+    ranges: usize,
+    range_count: usize,
+    cache: Vec<OnceLock<Vec<OnceLock<NetRow>>>>,
+}
+
+// a row containing an address, network is set when the row is a network
+pub struct RowMatch<'a> {
+    pub start: Addr,
+    pub end: Addr,
+    pub network: Option<Ip>,
+    pub row: &'a NetRow,
+}
+
+impl RowMatch<'_> {
+    // network/cidr, or start-end for ranges
+    pub fn prefix(&self) -> String {
+        match &self.network {
+            Some(net) => format!("{}/{}", net, net.cidr),
+            None => format!("{}-{}", self.start, self.end),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
-    pub interface_names: Vec<InterfaceAddress>,
-    pub hm: HashMap<String, String>,
+    pub interface_names: Arc<Vec<InterfaceAddress>>,
+    pub hm: Arc<LookupCache>,
+    pub placeholders: Vec<Arc<dyn PlaceholderProvider>>,
+    pub formats: HashMap<String, String>,
+    // results rendered so far, %i
+    pub output_index: u128,
+    // %i is written as INDEX_MARK for the caller to number, when results are
+    // rendered out of order, and each result ends with RESULT_MARK
+    pub defer_index: bool,
+}
+
+pub const INDEX_MARK: &str = "\u{0}%i\u{0}";
+pub const RESULT_MARK: &str = "\u{1e}";
+
+// name and ptr answers, locked only to read or store so lookups can run
+// side by side
+#[derive(Debug, Default)]
+pub struct LookupCache {
+    map: RwLock<HashMap<String, String>>,
+}
+
+impl LookupCache {
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.map.read().unwrap().get(k).cloned()
+    }
+
+    pub fn insert(&self, k: String, v: String) {
+        self.map.write().unwrap().insert(k, v);
+    }
+}
+
+// what a placeholder provider can see when rendering, hm is the lookup cache
+pub struct PlaceholderContext<'a> {
+    pub ip: &'a Ip,
+    pub row: Option<&'a NetRow>,
+    pub hm: &'a LookupCache,
+    pub interface_names: &'a [InterfaceAddress],
 }
 
+// registered names are rendered with %{name}, even single characters, only
+// the built-in p, m, d and r are also %p, %m, %d and %r, and a provider
+// registered for one of those replaces the built-in
+pub trait PlaceholderProvider: fmt::Debug + Send + Sync {
+    fn names(&self) -> Vec<String>;
+    fn render(&self, name: &str, context: &mut PlaceholderContext) -> Option<String>;
+}
+
+#[derive(Debug)]
+pub struct PtrPlaceholder;
+
+#[derive(Debug)]
+pub struct InterfacePlaceholder;
+
+#[derive(Debug)]
+pub struct ReservationPlaceholder;
+
 pub enum FormatMode {
     Text,
     Binary,
@@ -52,6 +175,16 @@ pub enum FormatProcessor {
     None,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum FormatToken {
+    Literal(String),
+    Escape(char),
+    Placeholder { modifiers: String, key: char },
+    Field(String),
+    Unknown { modifiers: String, key: String },
+    Dangling(String),
+}
+
 pub enum Reverse {
     None,
     Input,
@@ -117,11 +250,227 @@ impl Ip {
     }
 }
 
-pub fn parse_mask(mask: &str) -> Option<u32> {
-    match mask.parse::<u32>() {
-        Ok(n) => Some(n),
-        Err(_) => None,
+impl Addr {
+    pub fn to_u128(&self) -> u128 {
+        match self {
+            Addr::V4(x) => u32::from(*x) as u128,
+            Addr::V6(x) => u128::from(*x),
+        }
+    }
+
+    // an address of the same family as self
+    pub fn with_u128(&self, n: u128) -> Addr {
+        match self {
+            Addr::V4(_) => Addr::V4(Ipv4Addr::from(n as u32)),
+            Addr::V6(_) => Addr::V6(Ipv6Addr::from(n)),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        match self {
+            Addr::V4(_) => 32,
+            Addr::V6(_) => 128,
+        }
+    }
+
+    pub fn same_family(&self, other: &Addr) -> bool {
+        matches!(
+            (self, other),
+            (Addr::V4(_), Addr::V4(_)) | (Addr::V6(_), Addr::V6(_))
+        )
+    }
+}
+
+impl NetRow {
+    // fields of the last --csv file to give the row are kept plain, those of
+    // earlier files as alias.field, _sources lists the files latest first
+    // when there is more than one
+    pub fn sources(&self) -> Vec<&str> {
+        match (self.row.get("_sources"), self.row.get("_source")) {
+            (Some(s), _) => s.split(' ').collect(),
+            (None, Some(s)) => vec![s.as_str()],
+            (None, None) => vec![],
+        }
+    }
+
+    // alias.field is only that file's field, a field the last file lacks is
+    // taken from the latest earlier file that has it
+    pub fn get(&self, field: &str) -> Option<&String> {
+        if let Some(v) = self.row.get(field) {
+            return Some(v);
+        }
+
+        let sources = self.sources();
+        let latest = sources.first()?;
+        if let Some(name) = field.strip_prefix(latest).and_then(|f| f.strip_prefix('.')) {
+            return self.row.get(name);
+        }
+        sources[1..]
+            .iter()
+            .find_map(|s| self.row.get(&format!("{}.{}", s, field)))
+    }
+}
+
+impl NetRows {
+    // ranges must be sorted before matching_rows can find them
+    pub fn sort_ranges(&mut self) {
+        self.ranges
+            .sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+
+        self.range_ends = vec![];
+        let mut highest: Option<&Addr> = None;
+        for r in &self.ranges {
+            highest = match highest {
+                Some(h) if h.same_family(&r.end) && *h >= r.end => Some(h),
+                _ => Some(&r.end),
+            };
+            self.range_ends.push(highest.unwrap().clone());
+        }
+    }
+
+    // every row, networks then ranges, with those held by the index
+    pub fn all_rows(&self) -> Vec<RowMatch<'_>> {
+        let mut v = vec![];
+        for (net, row) in &self.networks {
+            v.push(RowMatch {
+                start: net.address.clone(),
+                end: broadcast(net).address,
+                network: Some(net.clone()),
+                row,
+            });
+        }
+        if let Some(index) = &self.index {
+            for (net, id) in index.networks() {
+                v.push(RowMatch {
+                    start: net.address.clone(),
+                    end: broadcast(&net).address,
+                    network: Some(net),
+                    row: index.row(id),
+                });
+            }
+        }
+        for r in &self.ranges {
+            v.push(RowMatch {
+                start: r.start.clone(),
+                end: r.end.clone(),
+                network: None,
+                row: &r.row,
+            });
+        }
+        if let Some(index) = &self.index {
+            for i in 0..index.range_count {
+                let (start, end, _, id) = index.range(i);
+                v.push(RowMatch {
+                    start,
+                    end,
+                    network: None,
+                    row: index.row(id),
+                });
+            }
+        }
+        v
     }
+
+    // rows held by the index are read into networks and ranges
+    pub fn load_index(&mut self) {
+        if let Some(index) = self.index.take() {
+            for (net, id) in index.networks() {
+                self.networks.insert(net, index.row(id).clone());
+            }
+            for i in 0..index.range_count {
+                let (start, end, _, id) = index.range(i);
+                self.ranges.push(NetRange {
+                    start,
+                    end,
+                    row: index.row(id).clone(),
+                });
+            }
+            self.sort_ranges();
+        }
+    }
+}
+
+impl From<CsvIndex> for NetRows {
+    fn from(index: CsvIndex) -> Self {
+        NetRows {
+            index: Some(index),
+            ..NetRows::default()
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            interface_names: Arc::new(vec![]),
+            hm: Arc::new(LookupCache::default()),
+            placeholders: vec![
+                Arc::new(PtrPlaceholder),
+                Arc::new(InterfacePlaceholder),
+                Arc::new(ReservationPlaceholder),
+            ],
+            formats: HashMap::new(),
+            output_index: 0,
+            defer_index: false,
+        }
+    }
+}
+
+impl Config {
+    // later registrations take precedence over earlier ones and the built-ins
+    pub fn register_placeholder(&mut self, provider: Arc<dyn PlaceholderProvider>) {
+        self.placeholders.push(provider);
+    }
+
+    pub fn placeholder(&self, name: &str) -> Option<Arc<dyn PlaceholderProvider>> {
+        self.placeholders
+            .iter()
+            .rev()
+            .find(|p| p.names().iter().any(|n| n == name))
+            .cloned()
+    }
+
+    pub fn placeholder_names(&self) -> Vec<String> {
+        self.placeholders.iter().flat_map(|p| p.names()).collect()
+    }
+}
+
+impl PlaceholderProvider for PtrPlaceholder {
+    fn names(&self) -> Vec<String> {
+        vec!["p".to_string()]
+    }
+
+    fn render(&self, _name: &str, context: &mut PlaceholderContext) -> Option<String> {
+        Some(ptr_format(context.ip, context.hm))
+    }
+}
+
+impl PlaceholderProvider for InterfacePlaceholder {
+    fn names(&self) -> Vec<String> {
+        vec!["m".to_string(), "d".to_string()]
+    }
+
+    fn render(&self, name: &str, context: &mut PlaceholderContext) -> Option<String> {
+        Some(matching_network_interface(
+            context.ip,
+            context.interface_names,
+            name == "d",
+        ))
+    }
+}
+
+impl PlaceholderProvider for ReservationPlaceholder {
+    fn names(&self) -> Vec<String> {
+        vec!["r".to_string()]
+    }
+
+    fn render(&self, _name: &str, context: &mut PlaceholderContext) -> Option<String> {
+        network_reservation(context.ip)
+    }
+}
+
+pub fn parse_mask(mask: &str) -> Option<u32> {
+    mask.parse::<u32>().ok()
 }
 
 pub fn parse_v6(address: &str, input_base: Option<i32>, reverse: bool) -> Option<Addr> {
@@ -203,7 +552,7 @@ pub fn parse_v4(address: &str, input_base: Option<i32>, reverse: bool) -> Option
                 arr[x] = match u8::from_str_radix(y, base as u32) {
                     Ok(y) => y,
                     Err(e) => {
-                        println!("cannot convert {}: {}", y, e);
+                        eprintln!("cannot convert {}: {}", y, e);
                         return None;
                     }
                 }
@@ -243,7 +592,7 @@ pub fn parse_address_mask(
     default_v6_mask: Option<u32>,
     input_base: Option<i32>,
     reverse: bool,
-    config: &RefCell<Config>,
+    config: &Mutex<Config>,
 ) -> Option<Ip> {
     let parts: Vec<&str> = a.split('/').collect();
 
@@ -276,7 +625,14 @@ pub fn parse_address_mask(
         }
     }
 
-    let arg = ip_lookup(arg, &mut config.borrow_mut().hm);
+    // literal addresses need no lookup, which keeps them out of the shared cache
+    let arg = match arg.parse::<std::net::IpAddr>() {
+        Ok(_) => arg.to_string(),
+        Err(_) => {
+            let hm = config.lock().unwrap().hm.clone();
+            ip_lookup(arg, &hm)
+        }
+    };
 
     let input_ip = parse_v4_v6(&arg, input_base, reverse);
 
@@ -322,16 +678,10 @@ pub fn addresses<'a>(
                         x = a
                     };
 
-                    match &used {
-                        Some(map) => {
-                            if map
-                                .get(&Addr::V4(Ipv4Addr::from(u32::from(x) - 1)))
-                                .is_some()
-                            {
-                                continue;
-                            }
+                    if let Some(map) = &used {
+                        if map.contains_key(&Addr::V4(Ipv4Addr::from(u32::from(x) - 1))) {
+                            continue;
                         }
-                        None => {}
                     }
 
                     return Some(if mask.is_none() {
@@ -370,16 +720,10 @@ pub fn addresses<'a>(
                         x = a
                     };
 
-                    match &used {
-                        Some(map) => {
-                            if map
-                                .get(&Addr::V6(Ipv6Addr::from(u128::from(x) - 1)))
-                                .is_some()
-                            {
-                                continue;
-                            }
+                    if let Some(map) = &used {
+                        if map.contains_key(&Addr::V6(Ipv6Addr::from(u128::from(x) - 1))) {
+                            continue;
                         }
-                        None => {}
                     }
 
                     return Some(if mask.is_none() {
@@ -401,6 +745,97 @@ pub fn addresses<'a>(
     })
 }
 
+// where a walk of addresses or subnets starts, how far it moves each time and
+// how many it gives, offset is counted from the end when descending
+#[derive(Debug, Clone)]
+pub struct Walk {
+    pub offset: u128,
+    pub limit: Option<u128>,
+    pub step: u128,
+    pub descending: bool,
+}
+
+impl Default for Walk {
+    fn default() -> Self {
+        Walk {
+            offset: 0,
+            limit: None,
+            step: 1,
+            descending: false,
+        }
+    }
+}
+
+// addresses, or subnets of mask, found by position so that the offset is
+// reached without walking up to it. used is sorted and merged, as from
+// merge_ranges, and positions that overlap it are jumped over
+pub fn walk_addresses<'a>(
+    ip: &Ip,
+    used: Option<&'a [(Addr, Addr)]>,
+    mask: Option<u32>,
+    walk: &Walk,
+) -> impl std::iter::Iterator<Item = Ip> + 'a {
+    let cidr = mask.unwrap_or(ip.cidr);
+    let shift = match mask {
+        Some(m) => ip.address.width() - m,
+        None => 0,
+    };
+    let n = network(&Ip {
+        address: ip.address.clone(),
+        cidr: cidr.min(ip.cidr),
+    });
+    let last = (broadcast(&n).address.to_u128() - n.address.to_u128())
+        .checked_shr(shift)
+        .unwrap_or(0);
+    let base = n.address.to_u128();
+    let span = u128::MAX.checked_shr(128 - shift).unwrap_or(0);
+    let used = used.unwrap_or(&[]);
+    let Walk {
+        offset,
+        mut limit,
+        step,
+        descending,
+    } = walk.clone();
+
+    let mut k: u128 = 0;
+    std::iter::from_fn(move || loop {
+        if limit == Some(0) {
+            return None;
+        }
+        let p = k
+            .checked_mul(step)?
+            .checked_add(offset)
+            .filter(|p| *p <= last)?;
+        let idx = if descending { last - p } else { p };
+        let start = n
+            .address
+            .with_u128(base + idx.checked_shl(shift).unwrap_or(0));
+        let end = n.address.with_u128(start.to_u128() + span);
+
+        let i = used.partition_point(|(_, e)| *e < start);
+        match used.get(i) {
+            Some((s, e)) if *s <= end => {
+                // the next position clear of this used range
+                let target = if descending {
+                    let below = (s.to_u128().checked_sub(base)? >> shift).checked_sub(1)?;
+                    last - below
+                } else {
+                    ((e.to_u128() - base) >> shift).checked_add(1)?
+                };
+                k = (target - offset).div_ceil(step);
+            }
+            _ => {
+                k += 1;
+                limit = limit.map(|l| l - 1);
+                return Some(Ip {
+                    address: start,
+                    cidr,
+                });
+            }
+        }
+    })
+}
+
 pub fn smallest_group_network(networks: &HashMap<Ip, bool>) -> Option<Ip> {
     if networks.is_empty() {
         return None;
@@ -493,6 +928,19 @@ pub fn smallest_group_network_limited(networks: &HashMap<Ip, bool>, cidr: u32) -
     Some(net_list.values().cloned().collect())
 }
 
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Addr::V4(x) => {
+                write!(f, "{}", x)
+            }
+            Addr::V6(x) => {
+                write!(f, "{}", x)
+            }
+        }
+    }
+}
+
 impl fmt::Display for Ip {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.address {
@@ -943,174 +1391,1794 @@ pub fn network_reservation(ip: &Ip) -> Option<String> {
     None
 }
 
-pub fn wildcard(ip: &Ip) -> Ip {
-    match ip.address {
-        Addr::V4(_x) => {
-            let mut bin: u32 = 0;
-            for i in 0..32 - ip.cidr {
-                bin |= 1 << i;
-            }
-            Ip {
-                address: Addr::V4(Ipv4Addr::from(bin)),
-                cidr: ip.cidr,
-            }
-        }
-        Addr::V6(_x) => {
-            let mut bin: u128 = 0;
-            for i in 0..128 - ip.cidr {
-                bin |= 1 << i;
-            }
-            Ip {
-                address: Addr::V6(Ipv6Addr::from(bin)),
-                cidr: ip.cidr,
-            }
-        }
+// the network covering exactly start to end, if there is one
+pub fn range_network(start: &Addr, end: &Addr) -> Option<Ip> {
+    if !start.same_family(end) || start > end {
+        return None;
     }
-}
 
-pub fn matching_network_interface(
-    ip: &Ip,
-    interfaces: &[InterfaceAddress],
-    ip_only: bool,
-) -> String {
-    let net = network(ip);
-    for ifaddr in interfaces {
-        if let Some(address) = ifaddr.address {
-            // println!("interface {} address {}", ifaddr.interface_name, address);
-            let ss: SockaddrStorage = address;
+    let span = end.to_u128() - start.to_u128();
+    if span & span.wrapping_add(1) != 0 || start.to_u128() & span != 0 {
+        return None;
+    }
 
-            match address.family() {
-                Some(AddressFamily::Inet) => {
-                    let if_addr_bin = ss.as_sockaddr_in().unwrap().ip();
+    Some(Ip {
+        address: start.clone(),
+        cidr: start.width() - span.count_ones(),
+    })
+}
 
-                    if ip_only {
-                        if let Addr::V4(x) = ip.address {
-                            if u32::from(if_addr_bin) == u32::from(x) {
-                                return ifaddr.interface_name.to_string();
-                            }
-                        }
-                        continue;
-                    }
+// the fewest networks covering start to end
+pub fn range_networks(start: &Addr, end: &Addr) -> Vec<Ip> {
+    let mut v = vec![];
+    if !start.same_family(end) || start > end {
+        return v;
+    }
 
-                    if let Some(netmask) = ifaddr.netmask {
-                        if let Addr::V4(x) = net.address {
-                            let bin = u32::from(x);
-                            let a = netmask.as_sockaddr_in().unwrap().ip();
+    let width = start.width();
+    let end = end.to_u128();
+    let mut cur = start.to_u128();
+    loop {
+        let aligned = if cur == 0 {
+            width
+        } else {
+            cur.trailing_zeros().min(width)
+        };
+        let fits = match (end - cur).checked_add(1) {
+            Some(n) => 127 - n.leading_zeros(),
+            None => 128,
+        };
+        let bits = aligned.min(fits);
 
-                            if (bin & u32::from(a)) == (u32::from(if_addr_bin) & u32::from(a)) {
-                                //println!("{:#?} in {} {}", ip, bin, a);
-                                return ifaddr.interface_name.to_string();
-                            }
-                        }
-                    }
-                }
-                Some(AddressFamily::Inet6) => {
-                    let if_addr_bin = u128::from(ss.as_sockaddr_in6().unwrap().ip());
+        v.push(Ip {
+            address: start.with_u128(cur),
+            cidr: width - bits,
+        });
 
-                    if ip_only {
-                        if let Addr::V6(x) = ip.address {
-                            if if_addr_bin == u128::from(x) {
-                                return ifaddr.interface_name.to_string();
-                            }
-                        }
-                        continue;
-                    }
+        if bits == 128 {
+            break;
+        }
+        match cur.checked_add(1 << bits) {
+            Some(n) if n <= end => cur = n,
+            _ => break,
+        }
+    }
+    v
+}
 
-                    if let Some(netmask) = ifaddr.netmask {
-                        if let Addr::V6(x) = net.address {
-                            let bin = u128::from(x);
-                            let a = u128::from(netmask.as_sockaddr_in6().unwrap().ip());
-                            if (bin & a) == (if_addr_bin & a) {
-                                // println!("{:#?} block {} {}", ip, bin, a);
-                                return ifaddr.interface_name.to_string();
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    continue;
+// sort and join overlapping or adjacent ranges
+pub fn merge_ranges(mut ranges: Vec<(Addr, Addr)>) -> Vec<(Addr, Addr)> {
+    ranges.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mut v: Vec<(Addr, Addr)> = vec![];
+    for (start, end) in ranges {
+        if let Some(last) = v.last_mut() {
+            if last.1.same_family(&start)
+                && last
+                    .1
+                    .to_u128()
+                    .checked_add(1)
+                    .is_none_or(|n| n >= start.to_u128())
+            {
+                if end > last.1 {
+                    last.1 = end;
                 }
+                continue;
             }
         }
+        v.push((start, end));
     }
-    "".to_string()
+    v
 }
 
-pub fn rbl_format(ip: &Ip) -> String {
-    match ip.address {
-        Addr::V4(x) => {
-            let f = format!("{}", x);
-            let v: Vec<&str> = f.rsplit('.').collect();
-            v.join(".")
+// a csv row filter, field=value, field!=value or field~regex
+#[derive(Debug, Clone)]
+pub enum RowPredicate {
+    Equals(String, String),
+    NotEquals(String, String),
+    Matches(String, Regex),
+}
+
+impl RowPredicate {
+    pub fn parse(s: &str) -> Result<RowPredicate, String> {
+        let pos = s.find(['=', '~']);
+        match pos {
+            Some(i) if s[i..].starts_with('~') => match Regex::new(&s[i + 1..]) {
+                Ok(r) => Ok(RowPredicate::Matches(s[..i].to_string(), r)),
+                Err(e) => Err(format!("{}: {}", s, e)),
+            },
+            Some(i) if i > 0 && s[..i].ends_with('!') => Ok(RowPredicate::NotEquals(
+                s[..i - 1].to_string(),
+                s[i + 1..].to_string(),
+            )),
+            Some(i) => Ok(RowPredicate::Equals(
+                s[..i].to_string(),
+                s[i + 1..].to_string(),
+            )),
+            None => Err(format!("{}: not in field=value or field~regex format", s)),
         }
-        Addr::V6(x) => {
-            let mut v = vec![];
-            let octet = x.octets();
-            for o in octet.iter().rev() {
-                let r = format!("{:02x}", o);
+    }
 
-                v.push(r.get(1..2).unwrap().to_string());
-                v.push(r.get(0..1).unwrap().to_string());
-            }
-            v.join(".")
+    pub fn field(&self) -> &str {
+        match self {
+            RowPredicate::Equals(f, _) | RowPredicate::NotEquals(f, _) => f,
+            RowPredicate::Matches(f, _) => f,
+        }
+    }
+
+    pub fn test(&self, row: &NetRow) -> bool {
+        let value = row.get(self.field());
+        match self {
+            RowPredicate::Equals(_, v) => value == Some(v),
+            RowPredicate::NotEquals(_, v) => value != Some(v),
+            RowPredicate::Matches(_, r) => value.is_some_and(|x| r.is_match(x)),
         }
     }
 }
 
-pub fn ip_lookup(address: &str, hm: &mut HashMap<String, String>) -> String {
+// rows matching every predicate, in address order
+pub fn query_rows<'a>(rows: &'a NetRows, predicates: &[RowPredicate]) -> Vec<RowMatch<'a>> {
+    let mut v: Vec<RowMatch> = rows
+        .all_rows()
+        .into_iter()
+        .filter(|m| predicates.iter().all(|p| p.test(m.row)))
+        .collect();
+
+    v.sort_by(|a, b| (&a.start, &b.end).partial_cmp(&(&b.start, &a.end)).unwrap());
+    v
+}
+
+// the parts of net not covered by any used range
+pub fn free_ranges(net: &Ip, used: Vec<(Addr, Addr)>) -> Vec<(Addr, Addr)> {
+    let start = network(net).address;
+    let end = broadcast(net).address;
+
+    let mut v = vec![];
+    let mut cur = Some(start.to_u128());
+    for (s, e) in merge_ranges(used) {
+        if !s.same_family(&start) || e < start || s > end {
+            continue;
+        }
+        let c = match cur {
+            Some(c) => c,
+            None => break,
+        };
+        if s.to_u128() > c {
+            v.push((start.with_u128(c), start.with_u128(s.to_u128() - 1)));
+        }
+        cur = e.to_u128().checked_add(1);
+    }
+    if let Some(c) = cur {
+        if c <= end.to_u128() {
+            v.push((start.with_u128(c), end));
+        }
+    }
+    v
+}
+
+// observed addresses within a csv row
+#[derive(Debug, Default)]
+pub struct RowUsage {
+    pub hits: u64,
+    pub seen: Vec<(Addr, Addr)>,
+    pub first: Option<Ip>,
+    pub last: Option<Ip>,
+}
+
+impl RowUsage {
+    pub fn add(&mut self, ip: &Ip) {
+        self.hits += 1;
+        self.seen.push((network(ip).address, broadcast(ip).address));
+        if self.first.is_none() {
+            self.first = Some(ip.clone());
+        }
+        self.last = Some(ip.clone());
+
+        // keep memory down for long inputs, merging less often as it grows
+        if self.seen.len() >= 65536 && self.seen.len().is_power_of_two() {
+            self.seen = merge_ranges(std::mem::take(&mut self.seen));
+        }
+    }
+
+    // addresses seen at least once
+    pub fn distinct(&self) -> Count {
+        merge_ranges(self.seen.clone())
+            .iter()
+            .map(|(s, e)| Count::span(s, e))
+            .sum()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Fit {
+    First,
+    Best,
+}
+
+// a free network of size cidr in pool, starting on an align boundary if given
+pub fn allocate(
+    pool: &Ip,
+    used: Vec<(Addr, Addr)>,
+    cidr: u32,
+    align: Option<u32>,
+    fit: &Fit,
+) -> Option<Ip> {
+    let width = pool.address.width();
+    if cidr > width || cidr < pool.cidr {
+        return None;
+    }
+
+    let step_bits = width - cidr.min(align.unwrap_or(cidr));
+    let size_bits = width - cidr;
+
+    let mut best: Option<(u128, Ip)> = None;
+    for (start, end) in free_ranges(pool, used) {
+        let (s, e) = (start.to_u128(), end.to_u128());
+
+        // the first step boundary at or after s
+        let a = if step_bits == 128 {
+            if s != 0 {
+                continue;
+            }
+            0
+        } else {
+            let step = 1u128 << step_bits;
+            match s.checked_add(step - 1) {
+                Some(n) => n & !(step - 1),
+                None => continue,
+            }
+        };
+        let last = if size_bits == 128 {
+            Some(u128::MAX)
+        } else {
+            a.checked_add((1u128 << size_bits) - 1)
+        };
+        if a < s || last.is_none_or(|l| l > e) {
+            continue;
+        }
+
+        let ip = Ip {
+            address: start.with_u128(a),
+            cidr,
+        };
+        match fit {
+            Fit::First => return Some(ip),
+            Fit::Best => {
+                if best.as_ref().is_none_or(|(span, _)| e - s < *span) {
+                    best = Some((e - s, ip));
+                }
+            }
+        }
+    }
+    best.map(|(_, ip)| ip)
+}
+
+// the smallest cidr with room for hosts, v4 networks lose two addresses
+pub fn cidr_for_hosts(address: &Addr, hosts: u128) -> Option<u32> {
+    let width = address.width();
+    let needed = match address {
+        Addr::V4(_) => hosts.checked_add(2)?,
+        Addr::V6(_) => hosts.max(1),
+    };
+
+    let bits = 128 - (needed - 1).leading_zeros();
+    if bits > width {
+        return None;
+    }
+    Some(width - bits)
+}
+
+// carve net into the smallest networks for each named host count, largest
+// first so that they stay aligned
+pub fn vlsm(net: &Ip, needs: &[(String, u128)]) -> Result<Vec<(String, u128, Ip)>, String> {
+    let mut sized = vec![];
+    for (name, hosts) in needs {
+        match cidr_for_hosts(&net.address, *hosts) {
+            Some(cidr) => sized.push((name, hosts, cidr)),
+            None => return Err(format!("{}:{} is too many hosts", name, hosts)),
+        }
+    }
+    sized.sort_by_key(|(_, _, cidr)| *cidr);
+
+    let mut used = vec![];
+    let mut v = vec![];
+    for (name, hosts, cidr) in sized {
+        match allocate(net, used.clone(), cidr, None, &Fit::First) {
+            Some(ip) => {
+                used.push((ip.address.clone(), broadcast(&ip).address));
+                v.push((name.to_string(), *hosts, ip));
+            }
+            None => {
+                return Err(format!(
+                    "{}:{} needs a /{} and there is no room left in {}/{}",
+                    name, hosts, cidr, net, net.cidr
+                ))
+            }
+        }
+    }
+    Ok(v)
+}
+
+// a count as decimal, 0x hex or 2^N
+pub fn parse_count(s: &str) -> Option<u128> {
+    if let Some((base, exp)) = s.split_once('^') {
+        return base.parse::<u128>().ok()?.checked_pow(exp.parse().ok()?);
+    }
+    match s.strip_prefix("0x") {
+        Some(hex) => u128::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+// one level of an addressing plan, the numbers it takes are every one that
+// fits in its bits or the listed ranges
+#[derive(Clone, Debug)]
+pub struct PlanLevel {
+    pub name: String,
+    pub bits: u32,
+    pub values: Option<Vec<(u128, u128)>>,
+    pub hex: bool,
+}
+
+impl PlanLevel {
+    pub fn count(&self) -> u128 {
+        match &self.values {
+            Some(values) => values.iter().map(|(s, e)| e - s + 1).sum(),
+            None => 1u128 << self.bits,
+        }
+    }
+
+    pub fn nth(&self, mut n: u128) -> u128 {
+        match &self.values {
+            Some(values) => {
+                for (s, e) in values {
+                    if n <= e - s {
+                        return s + n;
+                    }
+                    n -= e - s + 1;
+                }
+                unreachable!()
+            }
+            None => n,
+        }
+    }
+
+    pub fn number(&self, n: u128) -> String {
+        match self.hex {
+            true => format!("{:x}", n),
+            false => n.to_string(),
+        }
+    }
+}
+
+// levels as name:bits or name:bits=values, values are numbers or ranges
+// separated by commas, given as 0x they are shown in hex
+pub fn parse_plan(spec: &str) -> Result<Vec<PlanLevel>, String> {
+    let mut levels = vec![];
+    for level in spec.split_whitespace() {
+        let (level_bits, values) = match level.split_once('=') {
+            Some((l, v)) => (l, Some(v)),
+            None => (level, None),
+        };
+        let (name, bits) = match level_bits
+            .split_once(':')
+            .map(|(n, b)| (n, b.parse::<u32>()))
+        {
+            Some((name, Ok(bits))) if !name.is_empty() && bits > 0 && bits < 128 => (name, bits),
+            _ => return Err(format!("{}: not in name:bits format", level)),
+        };
+
+        let mut hex = false;
+        let values = match values {
+            None => None,
+            Some(values) => {
+                let mut v = vec![];
+                for value in values.split(',') {
+                    hex |= value.starts_with("0x");
+                    let (s, e) = value.split_once('-').unwrap_or((value, value));
+                    match (parse_count(s), parse_count(e)) {
+                        (Some(s), Some(e)) if s <= e && e >> bits == 0 => v.push((s, e)),
+                        _ => {
+                            return Err(format!(
+                                "{}: {} is not a number or range in {} bits",
+                                name, value, bits
+                            ))
+                        }
+                    }
+                }
+                Some(v)
+            }
+        };
+
+        levels.push(PlanLevel {
+            name: name.to_string(),
+            bits,
+            values,
+            hex,
+        });
+    }
+
+    if levels.is_empty() {
+        return Err("empty plan".to_string());
+    }
+    Ok(levels)
+}
+
+// the networks of the last level of a plan in net, found by index so that
+// large levels can be paged through without listing them
+pub struct Plan {
+    pub net: Ip,
+    pub levels: Vec<PlanLevel>,
+    pub len: u128,
+}
+
+impl Plan {
+    pub fn new(net: &Ip, levels: Vec<PlanLevel>) -> Result<Plan, String> {
+        let bits: u32 = levels.iter().map(|l| l.bits).sum();
+        if net.cidr + bits > net.address.width() {
+            return Err(format!(
+                "plan needs {} bits and {}/{} has {}",
+                bits,
+                net,
+                net.cidr,
+                net.address.width() - net.cidr
+            ));
+        }
+
+        let mut len: u128 = 1;
+        for level in &levels {
+            len = len
+                .checked_mul(level.count())
+                .ok_or("plan has too many networks")?;
+        }
+
+        Ok(Plan {
+            net: network(net),
+            levels,
+            len,
+        })
+    }
+
+    // the numbers of each level and the network for index
+    pub fn get(&self, mut index: u128) -> Option<(Vec<u128>, Ip)> {
+        if index >= self.len {
+            return None;
+        }
+
+        let mut numbers = vec![0; self.levels.len()];
+        for (i, level) in self.levels.iter().enumerate().rev() {
+            numbers[i] = level.nth(index % level.count());
+            index /= level.count();
+        }
+
+        let mut cidr = self.net.cidr;
+        let mut n = self.net.address.to_u128();
+        let width = self.net.address.width();
+        for (level, number) in self.levels.iter().zip(&numbers) {
+            cidr += level.bits;
+            n |= number << (width - cidr);
+        }
+
+        Some((
+            numbers,
+            Ip {
+                address: self.net.address.with_u128(n),
+                cidr,
+            },
+        ))
+    }
+
+    // the networks from index start up to end
+    pub fn range(&self, start: u128, end: u128) -> impl Iterator<Item = (Vec<u128>, Ip)> + '_ {
+        (start..end.min(self.len)).map_while(move |i| self.get(i))
+    }
+}
+
+// rfc 4193 local /48, the global id is the low 40 bits of the sha-1 of key
+pub fn ula_prefix(key: &[u8]) -> Ip {
+    let digest = sha1_smol::Sha1::from(key).digest().bytes();
+    let mut bin = [0u8; 16];
+    bin[0] = 0xfd;
+    bin[1..6].copy_from_slice(&digest[15..20]);
+    Ip {
+        address: Addr::V6(Ipv6Addr::from(bin)),
+        cidr: 48,
+    }
+}
+
+// the key of a 64-bit ntp time since the unix epoch and an eui-64
+pub fn ula_key(since_epoch: std::time::Duration, eui64: u64) -> Vec<u8> {
+    let seconds = since_epoch.as_secs().wrapping_add(2_208_988_800) as u32 as u64;
+    let fraction = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    let mut key = ((seconds << 32) | fraction).to_be_bytes().to_vec();
+    key.extend(eui64.to_be_bytes());
+    key
+}
+
+// eui-64 of a mac address, ff:fe in the middle and the u/l bit flipped
+pub fn eui64(mac: [u8; 6]) -> u64 {
+    u64::from_be_bytes([
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ])
+}
+
+// the first interface mac that is not all zero
+pub fn local_eui64() -> Option<u64> {
+    getifaddrs()
+        .ok()?
+        .filter_map(|ifaddr| ifaddr.address?.as_link_addr()?.addr())
+        .find(|mac| mac.iter().any(|b| *b != 0))
+        .map(eui64)
+}
+
+// v6 addresses on local interfaces
+pub fn local_v6_addresses() -> Vec<Addr> {
+    match getifaddrs() {
+        Ok(ifaddrs) => ifaddrs
+            .filter_map(|ifaddr| Some(Addr::V6(ifaddr.address?.as_sockaddr_in6()?.ip())))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+// rows or addresses that overlap net
+pub fn net_in_use(net: &Ip, rows: &Option<NetRows>, addresses: &[Addr]) -> bool {
+    let (start, end) = (network(net).address, broadcast(net).address);
+    let overlaps = |s: &Addr, e: &Addr| s.same_family(&start) && *s <= end && start <= *e;
+
+    if addresses.iter().any(|a| overlaps(a, a)) {
+        return true;
+    }
+    match rows {
+        Some(rows) => rows.all_rows().iter().any(|m| overlaps(&m.start, &m.end)),
+        None => false,
+    }
+}
+
+// the address index from the network address, or from the end when negative
+pub fn host_index(ip: &Ip, index: i128) -> Option<Ip> {
+    let n = network(ip).address.to_u128();
+    let b = broadcast(ip).address.to_u128();
+    let at = if index < 0 {
+        b.checked_sub(index.unsigned_abs() - 1)
+            .filter(|at| *at >= n)?
+    } else {
+        n.checked_add(index as u128).filter(|at| *at <= b)?
+    };
+    Some(Ip {
+        address: ip.address.with_u128(at),
+        cidr: ip.cidr,
+    })
+}
+
+// the first and last host offsets in net, v4 without the network and
+// broadcast, v6 without the subnet-router anycast and rfc 2526 anycast
+pub fn host_bounds(net: &Ip) -> (u128, u128) {
+    let bits = net.address.width() - net.cidr;
+    let last = u128::MAX.checked_shr(128 - bits).unwrap_or(0);
+    match net.address {
+        Addr::V4(_) if net.cidr < 31 => (1, last - 1),
+        Addr::V4(_) => (0, last),
+        Addr::V6(_) => (
+            (net.cidr < 127) as u128,
+            if net.cidr <= 64 { last - 128 } else { last },
+        ),
+    }
+}
+
+// a host in net picked by the sha-1 of name, when it is used the next free
+// host above it, wrapping round to the start
+pub fn assign(name: &str, net: &Ip, used: &[(Addr, Addr)]) -> Option<Ip> {
+    let net = network(net);
+    let (lo, hi) = host_bounds(&net);
+    let base = net.address.to_u128();
+
+    let digest = sha1_smol::Sha1::from(name).digest().bytes();
+    let mut h = [0u8; 16];
+    h.copy_from_slice(&digest[..16]);
+    let h = u128::from_be_bytes(h);
+    let mut offset = lo + h.checked_rem(hi - lo + 1).unwrap_or(h);
+
+    let used = merge_ranges(
+        used.iter()
+            .filter(|(s, _)| s.same_family(&net.address))
+            .cloned()
+            .collect(),
+    );
+
+    let mut wrapped = false;
+    loop {
+        let n = base + offset;
+        match used
+            .iter()
+            .find(|(s, e)| s.to_u128() <= n && n <= e.to_u128())
+        {
+            None => {
+                return Some(Ip {
+                    address: net.address.with_u128(n),
+                    cidr: net.cidr,
+                })
+            }
+            Some((_, e)) if e.to_u128() >= base + hi => {
+                if wrapped {
+                    return None;
+                }
+                wrapped = true;
+                offset = lo;
+            }
+            Some((_, e)) => offset = e.to_u128() - base + 1,
+        }
+    }
+}
+
+// inner is inside outer, using within() when both are networks
+pub fn range_within(outer: (&Addr, &Addr), inner: (&Addr, &Addr)) -> bool {
+    match (
+        range_network(outer.0, outer.1),
+        range_network(inner.0, inner.1),
+    ) {
+        (Some(o), Some(i)) => within(&o, &i),
+        _ => outer.0.same_family(inner.0) && outer.0 <= inner.0 && inner.1 <= outer.1,
+    }
+}
+
+// how a network changed between two lists, by index into them
+#[derive(Debug, PartialEq)]
+pub enum NetChange {
+    Added(usize),
+    Removed(usize),
+    // the same network with different fields
+    Changed(usize, usize),
+    Split(usize, Vec<usize>),
+    Merged(Vec<usize>, usize),
+}
+
+pub fn diff_networks(old: &[NetRange], new: &[NetRange]) -> Vec<NetChange> {
+    let mut changes = vec![];
+
+    let mut by_range: HashMap<(&Addr, &Addr), Vec<usize>> = HashMap::new();
+    for (i, o) in old.iter().enumerate().rev() {
+        by_range.entry((&o.start, &o.end)).or_default().push(i);
+    }
+
+    let mut old_left = vec![true; old.len()];
+    let mut new_left = vec![true; new.len()];
+    for (j, n) in new.iter().enumerate() {
+        if let Some(i) = by_range.get_mut(&(&n.start, &n.end)).and_then(|v| v.pop()) {
+            old_left[i] = false;
+            new_left[j] = false;
+            if old[i].row.row != n.row.row {
+                changes.push(NetChange::Changed(i, j));
+            }
+        }
+    }
+
+    let removed: Vec<usize> = (0..old.len()).filter(|i| old_left[*i]).collect();
+    let added: Vec<usize> = (0..new.len()).filter(|j| new_left[*j]).collect();
+
+    // a network that went away with new ones inside it was split
+    for &i in &removed {
+        let parts: Vec<usize> = added
+            .iter()
+            .copied()
+            .filter(|j| {
+                new_left[*j]
+                    && range_within((&old[i].start, &old[i].end), (&new[*j].start, &new[*j].end))
+            })
+            .collect();
+        if !parts.is_empty() {
+            old_left[i] = false;
+            for j in &parts {
+                new_left[*j] = false;
+            }
+            changes.push(NetChange::Split(i, parts));
+        }
+    }
+
+    // a new network holding ones that went away is where they were merged
+    for &j in &added {
+        if !new_left[j] {
+            continue;
+        }
+        let parts: Vec<usize> = removed
+            .iter()
+            .copied()
+            .filter(|i| {
+                old_left[*i]
+                    && range_within((&new[j].start, &new[j].end), (&old[*i].start, &old[*i].end))
+            })
+            .collect();
+        if !parts.is_empty() {
+            new_left[j] = false;
+            for i in &parts {
+                old_left[*i] = false;
+            }
+            changes.push(NetChange::Merged(parts, j));
+        }
+    }
+
+    for (i, left) in old_left.iter().enumerate() {
+        if *left {
+            changes.push(NetChange::Removed(i));
+        }
+    }
+    for (j, left) in new_left.iter().enumerate() {
+        if *left {
+            changes.push(NetChange::Added(j));
+        }
+    }
+
+    // in address order
+    let first = |c: &NetChange| match c {
+        NetChange::Added(j) | NetChange::Merged(_, j) => (&new[*j].start, &new[*j].end),
+        NetChange::Removed(i) | NetChange::Changed(i, _) | NetChange::Split(i, _) => {
+            (&old[*i].start, &old[*i].end)
+        }
+    };
+    changes.sort_by(|a, b| {
+        let (a, b) = (first(a), first(b));
+        (a.0, b.1).partial_cmp(&(b.0, a.1)).unwrap()
+    });
+    changes
+}
+
+// only the rows matching every predicate
+pub fn filter_rows(rows: &NetRows, predicates: &[RowPredicate]) -> NetRows {
+    let mut filtered = NetRows::default();
+    for m in rows.all_rows() {
+        if !predicates.iter().all(|p| p.test(m.row)) {
+            continue;
+        }
+        match m.network {
+            Some(net) => {
+                filtered.networks.insert(net, m.row.clone());
+            }
+            None => filtered.ranges.push(NetRange {
+                start: m.start,
+                end: m.end,
+                row: m.row.clone(),
+            }),
+        }
+    }
+    filtered.sort_ranges();
+    filtered
+}
+
+// the smallest row holding all of ip, as within() does for networks
+pub fn row_within<'a>(rows: &'a NetRows, ip: &Ip) -> Option<RowMatch<'a>> {
+    let b = broadcast(ip).address;
+    matching_rows(&network(ip), rows)
+        .into_iter()
+        .find(|m| m.end >= b)
+}
+
+// rows that contain ip, the smallest first
+pub fn matching_rows<'a>(ip: &Ip, rows: &'a NetRows) -> Vec<RowMatch<'a>> {
+    let width = ip.address.width();
+
+    let mut v = vec![];
+    for i in 0..=width {
+        let mn = network(&Ip {
+            address: ip.address.clone(),
+            cidr: width - i,
+        });
+
+        if let Some(net_row) = rows.networks.get(&mn) {
+            v.push(RowMatch {
+                start: mn.address.clone(),
+                end: broadcast(&mn).address,
+                network: Some(mn),
+                row: net_row,
+            });
+        }
+    }
+
+    // walk back from the last range starting at or before ip until no
+    // earlier range can reach it, range_ends is only right once sort_ranges
+    // has been called
+    let a = &ip.address;
+    let mut i = rows.ranges.partition_point(|r| r.start <= *a);
+    while i > 0 {
+        i -= 1;
+        let r = &rows.ranges[i];
+        if !r.start.same_family(a) || *rows.range_ends.get(i).unwrap_or(&r.end) < *a {
+            break;
+        }
+
+        if r.end >= *a {
+            v.push(RowMatch {
+                start: r.start.clone(),
+                end: r.end.clone(),
+                network: None,
+                row: &r.row,
+            });
+        }
+    }
+
+    if let Some(index) = &rows.index {
+        v.extend(index.matching_rows(ip));
+    }
+
+    // networks before ranges of the same size, later ranges before earlier
+    v.sort_by_key(|m| m.end.to_u128() - m.start.to_u128());
+    v
+}
+
+const INDEX_MAGIC: &[u8] = b"ripcalc index 1\n";
+const INDEX_NONE: u32 = u32::MAX;
+// family, start, end, highest end so far and row
+const INDEX_RANGE: usize = 1 + 16 * 3 + 4;
+const INDEX_NODE: usize = 16 + 4 * 4;
+const INDEX_CACHE: usize = 1024;
+
+fn index_family(a: &Addr) -> usize {
+    match a {
+        Addr::V4(_) => 0,
+        Addr::V6(_) => 1,
+    }
+}
+
+fn index_addr(family: u8, n: u128) -> Addr {
+    match family {
+        0 => Addr::V4(Ipv4Addr::from(n as u32)),
+        _ => Addr::V6(Ipv6Addr::from(n)),
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct IndexReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> IndexReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        match self.pos.checked_add(n) {
+            Some(end) if end <= self.buf.len() => {
+                let b = &self.buf[self.pos..end];
+                self.pos = end;
+                Ok(b)
+            }
+            _ => Err("index is truncated".to_string()),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut n = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.take(1)?[0];
+            n |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return Ok(n);
+            }
+        }
+        Err("index has a bad number".to_string())
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let n = self.u32()? as usize;
+        String::from_utf8(self.take(n)?.to_vec()).map_err(|x| x.to_string())
+    }
+
+    fn strs(&mut self) -> Result<Vec<String>, String> {
+        let n = self.u32()?;
+        (0..n).map(|_| self.str()).collect()
+    }
+}
+
+fn file_sha1(path: &str) -> Result<[u8; 20], String> {
+    let mut file = std::fs::File::open(path).map_err(|x| format!("{}: {}", path, x))?;
+    let mut sha1 = sha1_smol::Sha1::new();
+    let mut buf = vec![0; 65536];
+    loop {
+        match std::io::Read::read(&mut file, &mut buf) {
+            Ok(0) => break,
+            Ok(n) => sha1.update(&buf[..n]),
+            Err(x) => return Err(format!("{}: {}", path, x)),
+        }
+    }
+    Ok(sha1.digest().bytes())
+}
+
+impl IndexSource {
+    // the size and time of path, and the digest of its contents with hash
+    pub fn read(path: &str, hash: bool) -> Result<IndexSource, String> {
+        let meta = std::fs::metadata(path).map_err(|x| format!("{}: {}", path, x))?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Ok(IndexSource {
+            path: path.to_string(),
+            modified,
+            size: meta.len(),
+            sha1: if hash { file_sha1(path)? } else { [0; 20] },
+        })
+    }
+
+    // a file that was only touched has not changed
+    pub fn changed(&self) -> bool {
+        let now = match IndexSource::read(&self.path, false) {
+            Ok(x) => x,
+            Err(_) => return true,
+        };
+        if now.size != self.size {
+            return true;
+        }
+        if now.modified == self.modified {
+            return false;
+        }
+        file_sha1(&self.path) != Ok(self.sha1)
+    }
+}
+
+// a trie node that stands for prefix/depth
+struct IndexNode {
+    prefix: u128,
+    depth: u32,
+    row: u32,
+    zero: u32,
+    one: u32,
+}
+
+// nodes without a row that lead to only one other are left out, lookups
+// check the prefix of each node they reach instead
+fn pack_trie(
+    nodes: &[[u32; 3]],
+    node: u32,
+    depth: u32,
+    n: u128,
+    width: u32,
+    packed: &mut Vec<IndexNode>,
+) -> u32 {
+    if node == INDEX_NONE {
+        return INDEX_NONE;
+    }
+
+    let (mut node, mut depth, mut n) = (node, depth, n);
+    loop {
+        let [zero, one, row] = nodes[node as usize];
+        if row != INDEX_NONE || (zero == INDEX_NONE) == (one == INDEX_NONE) {
+            break;
+        }
+        if zero == INDEX_NONE {
+            n |= 1 << (width - 1 - depth);
+            node = one;
+        } else {
+            node = zero;
+        }
+        depth += 1;
+    }
+
+    let [zero, one, row] = nodes[node as usize];
+    let at = packed.len();
+    packed.push(IndexNode {
+        prefix: n,
+        depth,
+        row,
+        zero: INDEX_NONE,
+        one: INDEX_NONE,
+    });
+    if depth < width {
+        packed[at].zero = pack_trie(nodes, zero, depth + 1, n, width, packed);
+        let n = n | 1 << (width - 1 - depth);
+        packed[at].one = pack_trie(nodes, one, depth + 1, n, width, packed);
+    }
+    at as u32
+}
+
+// the index is the key and sources, the csv fields, the row field names,
+// then rows of name and value numbers and the values, each behind a table
+// of offsets, a path compressed binary trie of networks for each family
+// and the ranges in sort_ranges order, little endian with varint numbers
+// in rows
+pub fn compile_index(
+    rows: &NetRows,
+    fields: &[String],
+    sources: &[IndexSource],
+    key: &str,
+) -> Vec<u8> {
+    let mut networks: Vec<(&Ip, &NetRow)> = rows.networks.iter().collect();
+    networks.sort_by(|a, b| a.0.partial_cmp(b.0).unwrap());
+    let all: Vec<&NetRow> = networks
+        .iter()
+        .map(|x| x.1)
+        .chain(rows.ranges.iter().map(|r| &r.row))
+        .collect();
+
+    let mut names: Vec<&String> = all.iter().flat_map(|r| r.row.keys()).collect();
+    names.sort();
+    names.dedup();
+    let name_ids: HashMap<&String, u64> = names
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (*n, i as u64))
+        .collect();
+
+    let mut out = INDEX_MAGIC.to_vec();
+    put_str(&mut out, key);
+    put_u32(&mut out, sources.len() as u32);
+    for s in sources {
+        put_str(&mut out, &s.path);
+        put_u64(&mut out, s.modified.as_secs());
+        put_u32(&mut out, s.modified.subsec_nanos());
+        put_u64(&mut out, s.size);
+        out.extend_from_slice(&s.sha1);
+    }
+    for list in [fields.iter().collect::<Vec<&String>>(), names] {
+        put_u32(&mut out, list.len() as u32);
+        for s in list {
+            put_str(&mut out, s);
+        }
+    }
+
+    // values are kept once, rows often share them
+    let mut values: Vec<&String> = vec![];
+    let mut value_ids: HashMap<&String, u64> = HashMap::new();
+    let mut data = vec![];
+    put_u32(&mut out, all.len() as u32);
+    for r in &all {
+        put_u64(&mut out, data.len() as u64);
+        let mut pairs: Vec<(&String, &String)> = r.row.iter().collect();
+        pairs.sort();
+        put_varint(&mut data, pairs.len() as u64);
+        for (name, value) in pairs {
+            let id = *value_ids.entry(value).or_insert_with(|| {
+                values.push(value);
+                values.len() as u64 - 1
+            });
+            put_varint(&mut data, name_ids[name]);
+            put_varint(&mut data, id);
+        }
+    }
+    put_u64(&mut out, data.len() as u64);
+    out.extend_from_slice(&data);
+
+    data = vec![];
+    put_u32(&mut out, values.len() as u32);
+    for v in values {
+        put_u64(&mut out, data.len() as u64);
+        data.extend_from_slice(v.as_bytes());
+    }
+    put_u64(&mut out, data.len() as u64);
+    out.extend_from_slice(&data);
+
+    // each node is the next node for a 0 bit, for a 1 bit and its row
+    let mut nodes: Vec<[u32; 3]> = vec![];
+    let mut roots = [INDEX_NONE; 2];
+    for (id, (net, _)) in networks.iter().enumerate() {
+        let f = index_family(&net.address);
+        let width = net.address.width();
+        let n = network(net).address.to_u128();
+        if roots[f] == INDEX_NONE {
+            roots[f] = nodes.len() as u32;
+            nodes.push([INDEX_NONE; 3]);
+        }
+        let mut node = roots[f] as usize;
+        for depth in 0..net.cidr {
+            let bit = ((n >> (width - 1 - depth)) & 1) as usize;
+            if nodes[node][bit] == INDEX_NONE {
+                nodes[node][bit] = nodes.len() as u32;
+                nodes.push([INDEX_NONE; 3]);
+            }
+            node = nodes[node][bit] as usize;
+        }
+        nodes[node][2] = id as u32;
+    }
+
+    let mut packed = vec![];
+    for (f, root) in roots.iter_mut().enumerate() {
+        let width = if f == 0 { 32 } else { 128 };
+        *root = pack_trie(&nodes, *root, 0, 0, width, &mut packed);
+    }
+    put_u32(&mut out, roots[0]);
+    put_u32(&mut out, roots[1]);
+    put_u32(&mut out, packed.len() as u32);
+    for node in packed {
+        out.extend_from_slice(&node.prefix.to_le_bytes());
+        for n in [node.depth, node.row, node.zero, node.one] {
+            put_u32(&mut out, n);
+        }
+    }
+
+    // range_ends is only right once sort_ranges has been called
+    put_u32(&mut out, rows.ranges.len() as u32);
+    for (i, r) in rows.ranges.iter().enumerate() {
+        out.push(index_family(&r.start) as u8);
+        let reach = rows.range_ends.get(i).unwrap_or(&r.end);
+        for a in [&r.start, &r.end, reach] {
+            out.extend_from_slice(&a.to_u128().to_le_bytes());
+        }
+        put_u32(&mut out, (networks.len() + i) as u32);
+    }
+
+    out
+}
+
+impl CsvIndex {
+    pub fn open(path: &str) -> Result<CsvIndex, String> {
+        let file = std::fs::File::open(path).map_err(|x| format!("Cannot open {}: {}", path, x))?;
+        // indexes are replaced by rename rather than written in place, so the
+        // mapped file does not change underneath, one truncated in place by
+        // something else would raise SIGBUS when the lost pages are read
+        let map = unsafe { memmap2::Mmap::map(&file) }
+            .map_err(|x| format!("Cannot map {}: {}", path, x))?;
+
+        let mut r = IndexReader { buf: &map, pos: 0 };
+        let invalid = |x: String| format!("{}: {}", path, x);
+        if r.take(INDEX_MAGIC.len()).ok() != Some(INDEX_MAGIC) {
+            return Err(format!("{} is not a ripcalc index", path));
+        }
+
+        let key = r.str().map_err(invalid)?;
+        let mut sources = vec![];
+        for _ in 0..r.u32().map_err(invalid)? {
+            let mut source = || -> Result<IndexSource, String> {
+                Ok(IndexSource {
+                    path: r.str()?,
+                    modified: std::time::Duration::new(r.u64()?, r.u32()?),
+                    size: r.u64()?,
+                    sha1: r.take(20)?.try_into().unwrap(),
+                })
+            };
+            sources.push(source().map_err(invalid)?);
+        }
+        let fields = r.strs().map_err(invalid)?;
+        let names = r.strs().map_err(invalid)?;
+
+        let row_count = r.u32().map_err(invalid)? as usize;
+        let row_offsets = r.pos;
+        r.take(row_count * 8).map_err(invalid)?;
+        let data_len = r.u64().map_err(invalid)? as usize;
+        let row_data = r.pos;
+        r.take(data_len).map_err(invalid)?;
+
+        let value_count = r.u32().map_err(invalid)? as usize;
+        let value_offsets = r.pos;
+        r.take(value_count * 8).map_err(invalid)?;
+        let data_len = r.u64().map_err(invalid)? as usize;
+        let value_data = r.pos;
+        r.take(data_len).map_err(invalid)?;
+
+        let roots = [r.u32().map_err(invalid)?, r.u32().map_err(invalid)?];
+        let node_count = r.u32().map_err(invalid)? as usize;
+        let nodes = r.pos;
+        r.take(node_count * INDEX_NODE).map_err(invalid)?;
+
+        let range_count = r.u32().map_err(invalid)? as usize;
+        let ranges = r.pos;
+        r.take(range_count * INDEX_RANGE).map_err(invalid)?;
+
+        let cache = (0..row_count.div_ceil(INDEX_CACHE))
+            .map(|_| OnceLock::new())
+            .collect();
+
+        let index = CsvIndex {
+            map,
+            key,
+            sources,
+            fields,
+            names,
+            row_count,
+            row_offsets,
+            row_data,
+            value_count,
+            value_offsets,
+            value_data,
+            roots,
+            node_count,
+            nodes,
+            ranges,
+            range_count,
+            cache,
+        };
+        index.check().map_err(invalid)?;
+        Ok(index)
+    }
+
+    // offsets, row and node numbers and addresses are checked once here, so
+    // lookups can read the map without checking them again
+    fn check(&self) -> Result<(), String> {
+        for (offsets, count) in [
+            (self.row_offsets, self.row_count),
+            (self.value_offsets, self.value_count),
+        ] {
+            let mut last = 0;
+            for i in 0..=count {
+                let at = self.u64_at(offsets + i * 8);
+                if at < last {
+                    return Err("index has a bad offset".to_string());
+                }
+                last = at;
+            }
+        }
+
+        let fits = |family: u8, n: u128| family == 1 || n >> 32 == 0;
+        let row_ok = |row: u32| row == INDEX_NONE || (row as usize) < self.row_count;
+
+        // each node is reached once, so the trie cannot loop
+        let mut seen = vec![false; self.node_count];
+        for (family, root) in self.roots.iter().enumerate() {
+            let width = if family == 0 { 32 } else { 128 };
+            let mut stack = vec![(*root, 0)];
+            while let Some((node, depth)) = stack.pop() {
+                if node == INDEX_NONE {
+                    continue;
+                }
+                match seen.get_mut(node as usize) {
+                    Some(x) if !*x => *x = true,
+                    _ => return Err("index has a bad trie".to_string()),
+                }
+                let x = self.node(node);
+                if x.depth < depth || x.depth > width || !fits(family as u8, x.prefix) {
+                    return Err("index has a bad trie".to_string());
+                }
+                if !row_ok(x.row) {
+                    return Err("index has a bad row number".to_string());
+                }
+                stack.push((x.zero, x.depth + 1));
+                stack.push((x.one, x.depth + 1));
+            }
+        }
+
+        for i in 0..self.range_count {
+            let at = self.ranges + i * INDEX_RANGE;
+            let family = self.map[at];
+            let n = |k: usize| {
+                let at = at + 1 + k * 16;
+                u128::from_le_bytes(self.map[at..at + 16].try_into().unwrap())
+            };
+            if family > 1 || !(0..3).all(|k| fits(family, n(k))) {
+                return Err("index has a bad range".to_string());
+            }
+            let row = self.u32_at(at + 49);
+            if row == INDEX_NONE || !row_ok(row) {
+                return Err("index has a bad row number".to_string());
+            }
+        }
+
+        // rows are read as they are matched, one that cannot be is refused
+        // here rather than later read as a blank row
+        for i in 0..self.value_count {
+            self.value_str(i)?;
+        }
+        for id in 0..self.row_count {
+            self.row_cells(id as u32, |_, _| ())?;
+        }
+        Ok(())
+    }
+
+    // why the index no longer stands for the csv files of key, if it does not
+    pub fn stale(&self, key: &str) -> Option<String> {
+        if self.key != key {
+            return Some("compiled from other --csv options".to_string());
+        }
+        self.sources
+            .iter()
+            .find(|s| s.changed())
+            .map(|s| format!("{} has changed", s.path))
+    }
+
+    fn u32_at(&self, at: usize) -> u32 {
+        u32::from_le_bytes(self.map[at..at + 4].try_into().unwrap())
+    }
+
+    fn node(&self, n: u32) -> IndexNode {
+        let at = self.nodes + n as usize * INDEX_NODE;
+        IndexNode {
+            prefix: u128::from_le_bytes(self.map[at..at + 16].try_into().unwrap()),
+            depth: self.u32_at(at + 16),
+            row: self.u32_at(at + 20),
+            zero: self.u32_at(at + 24),
+            one: self.u32_at(at + 28),
+        }
+    }
+
+    // start, end, highest end so far and row of the ith range
+    fn range(&self, i: usize) -> (Addr, Addr, Addr, u32) {
+        let at = self.ranges + i * INDEX_RANGE;
+        let family = self.map[at];
+        let a = |n: usize| {
+            let at = at + 1 + n * 16;
+            index_addr(
+                family,
+                u128::from_le_bytes(self.map[at..at + 16].try_into().unwrap()),
+            )
+        };
+        (a(0), a(1), a(2), self.u32_at(at + 49))
+    }
+
+    // offsets are followed by the length of the data, which ends the last
+    fn u64_at(&self, at: usize) -> usize {
+        u64::from_le_bytes(self.map[at..at + 8].try_into().unwrap()) as usize
+    }
+
+    fn value_str(&self, id: usize) -> Result<&str, String> {
+        if id >= self.value_count {
+            return Err("index row has an unknown value".to_string());
+        }
+        let at = self.value_offsets + id * 8;
+        let (start, end) = (self.u64_at(at), self.u64_at(at + 8));
+        let mut r = IndexReader {
+            buf: &self.map[self.value_data..self.value_data + end],
+            pos: start,
+        };
+        let bytes = r.take(end.saturating_sub(start))?;
+        std::str::from_utf8(bytes).map_err(|x| x.to_string())
+    }
+
+    // the name and value numbers of each field of a row
+    fn row_cells(&self, id: u32, mut cell: impl FnMut(usize, usize)) -> Result<(), String> {
+        let at = self.row_offsets + id as usize * 8;
+        let mut r = IndexReader {
+            buf: &self.map[self.row_data..self.row_data + self.u64_at(at + 8)],
+            pos: self.u64_at(at),
+        };
+
+        for _ in 0..r.varint()? {
+            let (name, value) = (r.varint()? as usize, r.varint()? as usize);
+            if name >= self.names.len() {
+                return Err("index row has an unknown field".to_string());
+            }
+            if value >= self.value_count {
+                return Err("index row has an unknown value".to_string());
+            }
+            cell(name, value);
+        }
+        Ok(())
+    }
+
+    fn read_row(&self, id: u32) -> Result<NetRow, String> {
+        let mut cells = vec![];
+        self.row_cells(id, |name, value| cells.push((name, value)))?;
+
+        let mut row = HashMap::new();
+        for (name, value) in cells {
+            row.insert(
+                self.names[name].to_string(),
+                self.value_str(value)?.to_string(),
+            );
+        }
+        Ok(NetRow { row })
+    }
+
+    // check has read the fields and values of every row, so this cannot fail
+    fn row(&self, id: u32) -> &NetRow {
+        let id = id as usize;
+        let chunk = self.cache[id / INDEX_CACHE]
+            .get_or_init(|| (0..INDEX_CACHE).map(|_| OnceLock::new()).collect());
+        chunk[id % INDEX_CACHE].get_or_init(|| {
+            self.read_row(id as u32)
+                .expect("index rows are checked when it is opened")
+        })
+    }
+
+    // every network in the trie with its row
+    fn networks(&self) -> Vec<(Ip, u32)> {
+        let mut v = vec![];
+        for (family, root) in self.roots.iter().enumerate() {
+            let mut stack = vec![*root];
+            while let Some(node) = stack.pop() {
+                if node == INDEX_NONE {
+                    continue;
+                }
+                let x = self.node(node);
+                if x.row != INDEX_NONE {
+                    let address = index_addr(family as u8, x.prefix);
+                    v.push((
+                        Ip {
+                            address,
+                            cidr: x.depth,
+                        },
+                        x.row,
+                    ));
+                }
+                stack.push(x.zero);
+                stack.push(x.one);
+            }
+        }
+        v
+    }
+
+    // as matching_rows, networks are found through the trie and ranges by
+    // searching for the last that starts at or before ip
+    fn matching_rows(&self, ip: &Ip) -> Vec<RowMatch<'_>> {
+        let a = &ip.address;
+        let width = a.width();
+        let n = a.to_u128();
+
+        let mut v = vec![];
+        let mut node = self.roots[index_family(a)];
+        while node != INDEX_NONE {
+            let x = self.node(node);
+            if x.depth > width {
+                break;
+            }
+            let net = network(&Ip {
+                address: a.clone(),
+                cidr: x.depth,
+            });
+            if net.address.to_u128() != x.prefix {
+                break;
+            }
+            if x.row != INDEX_NONE {
+                v.push(RowMatch {
+                    start: net.address.clone(),
+                    end: broadcast(&net).address,
+                    network: Some(net),
+                    row: self.row(x.row),
+                });
+            }
+            if x.depth == width {
+                break;
+            }
+            node = if (n >> (width - 1 - x.depth)) & 1 == 0 {
+                x.zero
+            } else {
+                x.one
+            };
+        }
+
+        let (mut low, mut high) = (0, self.range_count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.range(mid).0 <= *a {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let mut i = low;
+        while i > 0 {
+            i -= 1;
+            let (start, end, reach, row) = self.range(i);
+            if !start.same_family(a) || reach < *a {
+                break;
+            }
+            if end >= *a {
+                v.push(RowMatch {
+                    start,
+                    end,
+                    network: None,
+                    row: self.row(row),
+                });
+            }
+        }
+        v
+    }
+}
+
+pub fn wildcard(ip: &Ip) -> Ip {
+    match ip.address {
+        Addr::V4(_x) => {
+            let mut bin: u32 = 0;
+            for i in 0..32 - ip.cidr {
+                bin |= 1 << i;
+            }
+            Ip {
+                address: Addr::V4(Ipv4Addr::from(bin)),
+                cidr: ip.cidr,
+            }
+        }
+        Addr::V6(_x) => {
+            let mut bin: u128 = 0;
+            for i in 0..128 - ip.cidr {
+                bin |= 1 << i;
+            }
+            Ip {
+                address: Addr::V6(Ipv6Addr::from(bin)),
+                cidr: ip.cidr,
+            }
+        }
+    }
+}
+
+pub fn matching_network_interface(
+    ip: &Ip,
+    interfaces: &[InterfaceAddress],
+    ip_only: bool,
+) -> String {
+    let net = network(ip);
+    for ifaddr in interfaces {
+        if let Some(address) = ifaddr.address {
+            // println!("interface {} address {}", ifaddr.interface_name, address);
+            let ss: SockaddrStorage = address;
+
+            match address.family() {
+                Some(AddressFamily::Inet) => {
+                    let if_addr_bin = ss.as_sockaddr_in().unwrap().ip();
+
+                    if ip_only {
+                        if let Addr::V4(x) = ip.address {
+                            if u32::from(if_addr_bin) == u32::from(x) {
+                                return ifaddr.interface_name.to_string();
+                            }
+                        }
+                        continue;
+                    }
+
+                    if let Some(netmask) = ifaddr.netmask {
+                        if let Addr::V4(x) = net.address {
+                            let bin = u32::from(x);
+                            let a = netmask.as_sockaddr_in().unwrap().ip();
+
+                            if (bin & u32::from(a)) == (u32::from(if_addr_bin) & u32::from(a)) {
+                                //println!("{:#?} in {} {}", ip, bin, a);
+                                return ifaddr.interface_name.to_string();
+                            }
+                        }
+                    }
+                }
+                Some(AddressFamily::Inet6) => {
+                    let if_addr_bin = u128::from(ss.as_sockaddr_in6().unwrap().ip());
+
+                    if ip_only {
+                        if let Addr::V6(x) = ip.address {
+                            if if_addr_bin == u128::from(x) {
+                                return ifaddr.interface_name.to_string();
+                            }
+                        }
+                        continue;
+                    }
+
+                    if let Some(netmask) = ifaddr.netmask {
+                        if let Addr::V6(x) = net.address {
+                            let bin = u128::from(x);
+                            let a = u128::from(netmask.as_sockaddr_in6().unwrap().ip());
+                            if (bin & a) == (if_addr_bin & a) {
+                                // println!("{:#?} block {} {}", ip, bin, a);
+                                return ifaddr.interface_name.to_string();
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    continue;
+                }
+            }
+        }
+    }
+    "".to_string()
+}
+
+pub fn rbl_format(ip: &Ip) -> String {
+    match ip.address {
+        Addr::V4(x) => {
+            let f = format!("{}", x);
+            let v: Vec<&str> = f.rsplit('.').collect();
+            v.join(".")
+        }
+        Addr::V6(x) => {
+            let mut v = vec![];
+            let octet = x.octets();
+            for o in octet.iter().rev() {
+                let r = format!("{:02x}", o);
+
+                v.push(r.get(1..2).unwrap().to_string());
+                v.push(r.get(0..1).unwrap().to_string());
+            }
+            v.join(".")
+        }
+    }
+}
+
+pub fn ip_lookup(address: &str, hm: &LookupCache) -> String {
     let k = format!("n/{}", address);
 
-    if let Some(v) = hm.get(&k) {
-        return v.clone();
+    if let Some(v) = hm.get(&k) {
+        return v;
+    }
+
+    if let Ok(buffer) = lookup_host(address) {
+        let arg = buffer[0].to_string();
+
+        hm.insert(k, arg.clone());
+        arg
+    } else {
+        "".to_string()
+    }
+}
+
+pub fn ptr_format(ip: &Ip, hm: &LookupCache) -> String {
+    let k = format!("p/{}", ip);
+
+    if let Some(v) = hm.get(&k) {
+        return v;
+    }
+
+    let j = match ip.address {
+        Addr::V4(x) => {
+            let ip: std::net::IpAddr = std::net::IpAddr::V4(x);
+            lookup_addr(&ip)
+        }
+        Addr::V6(x) => {
+            let ip: std::net::IpAddr = std::net::IpAddr::V6(x);
+            lookup_addr(&ip)
+        }
+    };
+
+    if let Ok(j) = j {
+        hm.insert(k, j.clone());
+
+        return j;
+    }
+
+    "".to_string()
+}
+
+// a number of addresses or networks, which can be 2^128 for ::/0 and more for
+// totals of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Count {
+    hi: u128,
+    lo: u128,
+}
+
+impl Count {
+    pub fn new(n: u128) -> Count {
+        Count { hi: 0, lo: n }
+    }
+
+    pub fn pow2(bits: u32) -> Count {
+        match bits {
+            0..=127 => Count::new(1 << bits),
+            _ => Count {
+                hi: 1 << (bits - 128),
+                lo: 0,
+            },
+        }
+    }
+
+    // addresses from start to end, both included
+    pub fn span(start: &Addr, end: &Addr) -> Count {
+        Count::new(end.to_u128() - start.to_u128()) + Count::new(1)
+    }
+
+    pub fn to_u128(&self) -> Option<u128> {
+        match self.hi {
+            0 => Some(self.lo),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.hi as f64 * 2f64.powi(128) + self.lo as f64
+    }
+
+    // 2^N when a power of two, otherwise the number
+    pub fn power(&self) -> String {
+        match (self.hi.count_ones(), self.lo.count_ones()) {
+            (0, 1) => format!("2^{}", self.lo.trailing_zeros()),
+            (1, 0) => format!("2^{}", 128 + self.hi.trailing_zeros()),
+            _ => self.to_string(),
+        }
+    }
+
+    // 1.8e19 for large numbers, small ones as they are
+    pub fn scientific(&self) -> String {
+        match self.to_u128() {
+            Some(n) if n < 10000 => n.to_string(),
+            _ => format!("{:.1e}", self.as_f64()),
+        }
+    }
+}
+
+impl From<u128> for Count {
+    fn from(n: u128) -> Count {
+        Count::new(n)
     }
+}
 
-    if let Ok(buffer) = lookup_host(address) {
-        let arg = buffer[0].to_string();
+impl PartialEq<u128> for Count {
+    fn eq(&self, other: &u128) -> bool {
+        self.hi == 0 && self.lo == *other
+    }
+}
 
-        hm.insert(k, arg.clone());
-        arg
-    } else {
-        "".to_string()
+impl std::ops::Add for Count {
+    type Output = Count;
+
+    fn add(self, other: Count) -> Count {
+        let (lo, carry) = self.lo.overflowing_add(other.lo);
+        Count {
+            hi: self.hi + other.hi + carry as u128,
+            lo,
+        }
     }
 }
 
-pub fn ptr_format(ip: &Ip, hm: &mut HashMap<String, String>) -> String {
-    let k: String = "".to_string();
+impl std::ops::AddAssign for Count {
+    fn add_assign(&mut self, other: Count) {
+        *self = *self + other;
+    }
+}
 
-    if let Some(v) = hm.get(&k) {
-        return v.clone();
+impl std::iter::Sum for Count {
+    fn sum<I: Iterator<Item = Count>>(iter: I) -> Count {
+        iter.fold(Count::default(), |t, n| t + n)
     }
+}
 
-    let j = match ip.address {
-        Addr::V4(x) => {
-            let ip: std::net::IpAddr = std::net::IpAddr::V4(x);
-            lookup_addr(&ip)
-        }
-        Addr::V6(x) => {
-            let ip: std::net::IpAddr = std::net::IpAddr::V6(x);
-            lookup_addr(&ip)
+impl fmt::Display for Count {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.hi == 0 {
+            return write!(f, "{}", self.lo);
         }
-    };
 
-    if let Ok(j) = j {
-        hm.insert(k, j.clone());
+        // long division of 64 bit limbs by 10^19
+        const CHUNK: u128 = 10_000_000_000_000_000_000;
+        let mut limbs = [
+            (self.hi >> 64) as u64,
+            self.hi as u64,
+            (self.lo >> 64) as u64,
+            self.lo as u64,
+        ];
+        let mut chunks = vec![];
+        while limbs.iter().any(|l| *l != 0) {
+            let mut rem: u128 = 0;
+            for l in limbs.iter_mut() {
+                let cur = (rem << 64) | *l as u128;
+                *l = (cur / CHUNK) as u64;
+                rem = cur % CHUNK;
+            }
+            chunks.push(rem as u64);
+        }
 
-        return j;
+        let mut s = chunks.pop().unwrap_or(0).to_string();
+        for c in chunks.iter().rev() {
+            s.push_str(&format!("{:019}", c));
+        }
+        write!(f, "{}", s)
     }
-
-    "".to_string()
 }
 
-pub fn network_size(ip: &Ip) -> u128 {
-    let start = network(ip);
-    let end = broadcast(ip);
+pub fn network_size(ip: &Ip) -> Count {
+    Count::span(&network(ip).address, &broadcast(ip).address)
+}
 
-    match (start.address, end.address) {
-        (Addr::V6(start), Addr::V6(end)) => return (u128::from(end) - u128::from(start)) + 1,
-        (Addr::V4(start), Addr::V4(end)) => return (u32::from(end) - u32::from(start)) as u128 + 1,
-        (_, _) => {}
+// %Pt as 2^N, %Et as 1.8e19
+pub fn formatted_count(count: &Count, modifiers: &str) -> String {
+    match modifiers.chars().last() {
+        Some('P') => count.power(),
+        Some('E') => count.scientific(),
+        _ => count.to_string(),
     }
-    1
 }
 
 pub fn formatted_address(ip: &Ip, mode: &FormatMode) -> String {
@@ -1125,134 +3193,411 @@ pub fn formatted_address(ip: &Ip, mode: &FormatMode) -> String {
     }
 }
 
-pub fn format_details(
-    ip: &Ip,
-    formatted: String,
-    rows: &Option<HashMap<Ip, NetRow>>,
-    subnet_size: Option<u32>,
-    matches: Option<&getopts::Matches>,
-    config: &RefCell<Config>,
-) -> Option<String> {
-    let ip = &mut ip.clone();
-    let mut reformatted = formatted;
+const FORMAT_MODIFIERS: &str = "BSlLxPE";
+const FORMAT_ADDRESSES: &str = "abnws";
+const COUNT_MODIFIERS: &str = "PE";
+const FORMAT_COUNTS: &str = "tN";
+const FORMAT_KEYS: &str = "abnwsctrdmpkDNoi%";
+
+impl FormatToken {
+    // the template text that produced this token
+    pub fn source(&self) -> String {
+        match self {
+            FormatToken::Literal(l) => l.to_string(),
+            FormatToken::Escape(k) => format!("\\{}", k),
+            FormatToken::Placeholder { modifiers, key } => format!("%{}{}", modifiers, key),
+            FormatToken::Field(f) => format!("%{{{}}}", f),
+            FormatToken::Unknown { modifiers, key } => format!("%{}{}", modifiers, key),
+            FormatToken::Dangling(modifiers) => format!("%{}", modifiers),
+        }
+    }
 
-    if rows.is_some() {
-        let mut found_match = false;
+    pub fn description(&self) -> String {
+        match self {
+            FormatToken::Literal(_) => "Literal text".to_string(),
+            FormatToken::Escape(k) => match k {
+                'n' => "Line break".to_string(),
+                't' => "Tab character".to_string(),
+                _ => format!("Literal {}", k),
+            },
+            FormatToken::Placeholder { modifiers, key } => {
+                let d = match key {
+                    'a' => "IP address string",
+                    'n' => "Network address string",
+                    's' => "Subnet address string",
+                    'w' => "Wildcard address string",
+                    'b' => "Broadcast address string",
+                    'c' => "CIDR mask",
+                    't' => "Network size",
+                    'r' => "Network reservation information (if available)",
+                    'd' => "Matching device interface by IP",
+                    'm' => "Matching media link interface by network",
+                    'p' => "PTR record",
+                    'k' => "RBL/reverse DNS-style format",
+                    'D' => "Network size (--networks)",
+                    'N' => "Number of subnets (--networks)",
+                    'o' => "Position of the address in its network",
+                    'i' => "Number of results before this one",
+                    _ => "%",
+                };
 
-        let rows = rows.as_ref().unwrap();
+                match modifiers.chars().last() {
+                    Some('B') => format!("{} (binary)", d),
+                    Some('S') => format!("{} (split binary at network boundary)", d),
+                    Some('l') => format!("{} (unsigned integer)", d),
+                    Some('L') => format!("{} (signed integer)", d),
+                    Some('x') => format!("{} (hex)", d),
+                    Some('P') => format!("{} (power of two)", d),
+                    Some('E') => format!("{} (scientific)", d),
+                    _ => d.to_string(),
+                }
+            }
+            FormatToken::Field(f) => match field_level(f) {
+                ("_prefix", 0) => "Matched network or range".to_string(),
+                ("_prefix", l) => format!("Matched network or range of parent {}", l),
+                (name, 0) => format!("CSV field {}", name),
+                (name, l) => format!("CSV field {} of parent {}", name, l),
+            },
+            FormatToken::Unknown { .. } => "Unknown placeholder".to_string(),
+            FormatToken::Dangling(_) => "%".to_string(),
+        }
+    }
+}
 
-        match ip.address {
-            Addr::V4(x) => {
-                let search_ip = x;
+pub fn format_tokens(formatted: &str) -> Vec<FormatToken> {
+    let mut tokens = vec![];
+    let mut literal = "".to_string();
+    let mut modifiers = "".to_string();
+    let mut format_processor = FormatProcessor::None;
+    let mut chars = formatted.chars();
 
-                for i in 0..32 {
-                    let cidr = 32 - i;
-                    let mn = network(&Ip {
-                        address: Addr::V4(search_ip),
-                        cidr,
-                    });
+    while let Some(k) = chars.next() {
+        match format_processor {
+            FormatProcessor::Percent => {
+                if FORMAT_MODIFIERS.contains(k) {
+                    modifiers.push(k);
+                    continue;
+                }
 
-                    let net_row = rows.get(&Ip {
-                        address: mn.address,
-                        cidr,
-                    });
+                format_processor = FormatProcessor::None;
+                let modifiers = std::mem::take(&mut modifiers);
 
-                    if net_row.is_none() {
+                if k == '{' && modifiers.is_empty() {
+                    let rest = chars.as_str();
+                    if let Some(end) = rest.find('}') {
+                        tokens.push(FormatToken::Field(rest[..end].to_string()));
+                        chars = rest[end + 1..].chars();
                         continue;
                     }
+                }
+
+                if FORMAT_KEYS.contains(k) {
+                    tokens.push(FormatToken::Placeholder { modifiers, key: k });
+                } else {
+                    tokens.push(FormatToken::Unknown {
+                        modifiers,
+                        key: k.to_string(),
+                    });
+                }
+                continue;
+            }
+            FormatProcessor::Backslash => {
+                format_processor = FormatProcessor::None;
+                tokens.push(FormatToken::Escape(k));
+                continue;
+            }
+            FormatProcessor::None => {}
+        }
+
+        match k {
+            '%' => {
+                format_processor = FormatProcessor::Percent;
+            }
+            '\\' => {
+                format_processor = FormatProcessor::Backslash;
+            }
+            _ => {
+                literal.push(k);
+                continue;
+            }
+        }
+
+        if !literal.is_empty() {
+            tokens.push(FormatToken::Literal(std::mem::take(&mut literal)));
+        }
+    }
 
-                    let net_row = net_row.unwrap();
-                    ip.cidr = cidr;
+    if !literal.is_empty() {
+        tokens.push(FormatToken::Literal(literal));
+    }
 
-                    for f in net_row.row.keys() {
-                        reformatted = reformatted
-                            .replace(&format!("%{{{}}}", f), net_row.row.get(f).unwrap());
-                    }
-                    found_match = true;
+    match format_processor {
+        FormatProcessor::Percent => tokens.push(FormatToken::Dangling(modifiers)),
+        FormatProcessor::Backslash => tokens.push(FormatToken::Literal("\\".to_string())),
+        FormatProcessor::None => {}
+    }
+
+    tokens
+}
 
-                    break;
+// fields is None when no csv is loaded
+pub fn lint_format(
+    formatted: &str,
+    fields: Option<&[String]>,
+    config: &Config,
+) -> Result<(), String> {
+    let placeholders = config.placeholder_names();
+
+    for token in format_tokens(formatted) {
+        match &token {
+            FormatToken::Placeholder { modifiers, key } => {
+                if modifiers.chars().any(|m| COUNT_MODIFIERS.contains(m)) {
+                    if modifiers.len() > 1 || !FORMAT_COUNTS.contains(*key) {
+                        return Err(format!(
+                            "{}: {} must be followed by t or N",
+                            token.source(),
+                            modifiers
+                        ));
+                    }
+                } else if !modifiers.is_empty() && !FORMAT_ADDRESSES.contains(*key) {
+                    return Err(format!(
+                        "{}: {} must be followed by one of a, b, n, s or w",
+                        token.source(),
+                        modifiers
+                    ));
                 }
             }
-            Addr::V6(x) => {
-                let search_ip = x;
+            FormatToken::Field(f) if placeholders.contains(f) => {}
+            FormatToken::Field(f) => match (fields, field_level(f).0) {
+                (Some(_), "_prefix") => {}
+                (Some(fields), name) => {
+                    if !fields.iter().any(|x| x == name) {
+                        return Err(format!("{}: {} is not a csv field", token.source(), name));
+                    }
+                }
+                (None, name) => {
+                    return Err(format!(
+                        "{}: no csv loaded for field {}",
+                        token.source(),
+                        name
+                    ));
+                }
+            },
+            FormatToken::Unknown { .. } => {
+                return Err(format!("{}: unknown placeholder", token.source()));
+            }
+            FormatToken::Dangling(modifiers) => {
+                if modifiers.chars().any(|m| COUNT_MODIFIERS.contains(m)) {
+                    return Err(format!(
+                        "{}: {} must be followed by t or N",
+                        token.source(),
+                        modifiers
+                    ));
+                }
+                if !modifiers.is_empty() {
+                    return Err(format!(
+                        "{}: {} must be followed by one of a, b, n, s or w",
+                        token.source(),
+                        modifiers
+                    ));
+                }
+            }
+            FormatToken::Literal(_) | FormatToken::Escape(_) => {}
+        }
+    }
 
-                for i in 0..128 {
-                    let cidr = 128 - i;
-                    let mn = network(&Ip {
-                        address: Addr::V6(search_ip),
-                        cidr,
-                    });
+    Ok(())
+}
 
-                    let net_row = rows.get(&Ip {
-                        address: mn.address,
-                        cidr,
-                    });
+// split owner@1 into the field and how many matches up to look
+pub fn field_level(field: &str) -> (&str, usize) {
+    if let Some((name, level)) = field.rsplit_once('@') {
+        if let Ok(level) = level.parse::<usize>() {
+            return (name, level);
+        }
+    }
+    (field, 0)
+}
 
-                    if net_row.is_none() {
-                        continue;
-                    }
+pub fn render_placeholder(
+    name: &str,
+    ip: &Ip,
+    row: Option<&NetRow>,
+    config: &Mutex<Config>,
+) -> Option<String> {
+    // take what the provider needs and let go of config before it renders,
+    // providers may do lookups
+    let (provider, hm, interface_names) = {
+        let config = config.lock().unwrap();
+        (
+            config.placeholder(name)?,
+            config.hm.clone(),
+            config.interface_names.clone(),
+        )
+    };
 
-                    ip.cidr = cidr;
+    provider.render(
+        name,
+        &mut PlaceholderContext {
+            ip,
+            row,
+            hm: &hm,
+            interface_names: &interface_names,
+        },
+    )
+}
 
-                    let net_row = net_row.unwrap();
+pub fn format_details(
+    ip: &Ip,
+    formatted: String,
+    rows: &Option<NetRows>,
+    subnet_size: Option<u32>,
+    matches: Option<&getopts::Matches>,
+    config: &Mutex<Config>,
+) -> Option<String> {
+    let ip = &mut ip.clone();
+    let mut matched_rows: Vec<RowMatch> = vec![];
 
-                    for f in net_row.row.keys() {
-                        reformatted = reformatted
-                            .replace(&format!("%{{{}}}", f), net_row.row.get(f).unwrap());
-                    }
-                    found_match = true;
+    if let Some(rows) = rows {
+        matched_rows = matching_rows(ip, rows);
 
-                    break;
+        match matched_rows.first() {
+            Some(m) => {
+                if let Some(net) = &m.network {
+                    ip.cidr = net.cidr;
                 }
             }
+            None => {
+                if let Some(m) = matches {
+                    if !m.opt_present("allowemptyrow") {
+                        return None;
+                    }
+                };
+            }
         }
-        if !found_match {
-            if let Some(m) = matches {
-                if !m.opt_present("allowemptyrow") {
-                    return None;
-                }
-            };
+    }
+    let tokens = format_tokens(&formatted);
+
+    // one output per match, each seeing the matches above it as parents
+    if matches.is_some_and(|m| m.opt_present("all-matches")) && !matched_rows.is_empty() {
+        let mut out_str = "".to_string();
+        for i in 0..matched_rows.len() {
+            let mut ip = ip.clone();
+            if let Some(net) = &matched_rows[i].network {
+                ip.cidr = net.cidr;
+            }
+            out_str.push_str(&render_tokens(
+                &ip,
+                &tokens,
+                &matched_rows[i..],
+                subnet_size,
+                config,
+            ));
         }
+        return Some(out_str);
     }
+
+    Some(render_tokens(
+        ip,
+        &tokens,
+        &matched_rows,
+        subnet_size,
+        config,
+    ))
+}
+
+// render a format for a row found other than by address, such as query_rows
+pub fn format_row(
+    row_match: &RowMatch,
+    formatted: &str,
+    subnet_size: Option<u32>,
+    config: &Mutex<Config>,
+) -> String {
+    let ip = match &row_match.network {
+        Some(net) => net.clone(),
+        None => Ip {
+            address: row_match.start.clone(),
+            cidr: row_match.start.width(),
+        },
+    };
+
+    render_tokens(
+        &ip,
+        &format_tokens(formatted),
+        std::slice::from_ref(row_match),
+        subnet_size,
+        config,
+    )
+}
+
+fn render_tokens(
+    ip: &Ip,
+    tokens: &[FormatToken],
+    matched_rows: &[RowMatch],
+    subnet_size: Option<u32>,
+    config: &Mutex<Config>,
+) -> String {
+    let matched_row = matched_rows.first().map(|m| m.row);
+
     let b = broadcast(ip);
     let n = network(ip);
     let s = subnet(ip);
     let w = wildcard(ip);
 
-    if let Some(r) = network_reservation(ip) {
-        reformatted = reformatted.replace("%r", &r);
-    }
-
-    let mut mode = FormatMode::Text;
     let mut out_str = "".to_string();
-    let chars: Vec<_> = reformatted.chars().collect();
 
-    let mut format_processor = FormatProcessor::None;
-    for k in chars {
-        match format_processor {
-            FormatProcessor::Percent => {
-                format_processor = FormatProcessor::None;
-                match k {
-                    'B' => {
-                        format_processor = FormatProcessor::Percent;
-                        mode = FormatMode::Binary;
-                    }
-                    'S' => {
-                        format_processor = FormatProcessor::Percent;
-                        mode = FormatMode::SplitBinary;
-                    }
-                    'l' => {
-                        format_processor = FormatProcessor::Percent;
-                        mode = FormatMode::Integer;
+    for token in tokens {
+        match token {
+            FormatToken::Literal(l) => {
+                out_str.push_str(l);
+            }
+            FormatToken::Escape(k) => match k {
+                'n' => {
+                    out_str.push('\n');
+                }
+                't' => {
+                    out_str.push('\t');
+                }
+                _ => {
+                    out_str.push(*k);
+                }
+            },
+            FormatToken::Field(f) => match field_level(f) {
+                ("_prefix", l) => {
+                    if let Some(m) = matched_rows.get(l) {
+                        out_str.push_str(&m.prefix());
                     }
-                    'L' => {
-                        format_processor = FormatProcessor::Percent;
-                        mode = FormatMode::SignedInteger;
+                }
+                (name, l) if l > 0 || name != f => {
+                    if let Some(v) = matched_rows.get(l).and_then(|m| m.row.get(name)) {
+                        out_str.push_str(v);
                     }
-                    'x' => {
-                        format_processor = FormatProcessor::Percent;
-                        mode = FormatMode::Hex;
+                }
+                _ => match matched_rows.iter().find_map(|m| m.row.get(f)) {
+                    Some(v) => {
+                        out_str.push_str(v);
                     }
+                    None => match render_placeholder(f, ip, matched_row, config) {
+                        Some(v) => {
+                            out_str.push_str(&v);
+                        }
+                        None => {
+                            out_str.push_str(&format!("{{{}}}", f));
+                        }
+                    },
+                },
+            },
+            FormatToken::Placeholder { modifiers, key } => {
+                let mode = match modifiers.chars().last() {
+                    Some('B') => FormatMode::Binary,
+                    Some('S') => FormatMode::SplitBinary,
+                    Some('l') => FormatMode::Integer,
+                    Some('L') => FormatMode::SignedInteger,
+                    Some('x') => FormatMode::Hex,
+                    _ => FormatMode::Text,
+                };
+
+                match key {
                     'a' => {
                         out_str.push_str(&formatted_address(ip, &mode));
                     }
@@ -1272,27 +3617,29 @@ pub fn format_details(
                         out_str.push_str(&ip.cidr.to_string());
                     }
                     't' => {
-                        out_str.push_str(&network_size(ip).to_string());
-                    }
-                    'm' => {
-                        out_str.push_str(&matching_network_interface(
-                            ip,
-                            &config.borrow().interface_names,
-                            false,
-                        ));
+                        out_str.push_str(&formatted_count(&network_size(ip), modifiers));
                     }
-                    'd' => {
-                        out_str.push_str(&matching_network_interface(
-                            ip,
-                            &config.borrow().interface_names,
-                            true,
-                        ));
+                    'm' | 'd' | 'p' | 'r' => {
+                        if let Some(v) =
+                            render_placeholder(&key.to_string(), ip, matched_row, config)
+                        {
+                            out_str.push_str(&v);
+                        }
                     }
                     'k' => {
                         out_str.push_str(&rbl_format(ip));
                     }
-                    'p' => {
-                        out_str.push_str(&ptr_format(ip, &mut config.borrow_mut().hm));
+                    'o' => {
+                        let ordinal = ip.address.to_u128() - n.address.to_u128();
+                        out_str.push_str(&ordinal.to_string());
+                    }
+                    'i' => {
+                        let config = config.lock().unwrap();
+                        if config.defer_index {
+                            out_str.push_str(INDEX_MARK);
+                        } else {
+                            out_str.push_str(&config.output_index.to_string());
+                        }
                     }
                     '%' => {
                         out_str.push('%');
@@ -1306,64 +3653,132 @@ pub fn format_details(
                     }
                     'N' => {
                         if let Some(s) = subnet_size {
-                            out_str.push_str(&subnets_in_network(s, ip).to_string());
+                            out_str
+                                .push_str(&formatted_count(&subnets_in_network(s, ip), modifiers));
                         } else {
                             out_str.push('N');
                         };
                     }
                     _ => {
-                        out_str.push(k);
-                    }
-                }
-                continue;
-            }
-            FormatProcessor::Backslash => {
-                format_processor = FormatProcessor::None;
-                match k {
-                    'n' => {
-                        out_str.push('\n');
-                        mode = FormatMode::Text;
-                    }
-                    't' => {
-                        out_str.push('\t');
-                        mode = FormatMode::Text;
-                    }
-                    '\\' => {
-                        out_str.push('\\');
-                        mode = FormatMode::Text;
-                    }
-                    _ => {
-                        out_str.push(k);
-                        mode = FormatMode::Text;
+                        out_str.push(*key);
                     }
                 }
-                continue;
-            }
-            FormatProcessor::None => {}
-        }
-
-        match k {
-            '%' => {
-                format_processor = FormatProcessor::Percent;
-                mode = FormatMode::Text;
             }
-            '\\' => {
-                format_processor = FormatProcessor::Backslash;
-                mode = FormatMode::Text;
+            FormatToken::Unknown { modifiers: _, key } => {
+                out_str.push_str(key);
             }
-            _ => {
-                out_str.push(k);
+            FormatToken::Dangling(_) => {
+                out_str.push('%');
             }
         }
     }
 
-    match format_processor {
-        FormatProcessor::Percent => out_str.push('%'),
-        FormatProcessor::Backslash => out_str.push('\\'),
-        FormatProcessor::None => {}
+    let mut config = config.lock().unwrap();
+    if config.defer_index {
+        out_str.push_str(RESULT_MARK);
+    } else {
+        config.output_index += 1;
     }
+    out_str
+}
+
+const WINDOWS_1252: [u16; 32] = [
+    0x20ac, 0x81, 0x201a, 0x192, 0x201e, 0x2026, 0x2020, 0x2021, 0x2c6, 0x2030, 0x160, 0x2039,
+    0x152, 0x8d, 0x17d, 0x8f, 0x90, 0x2018, 0x2019, 0x201c, 0x201d, 0x2022, 0x2013, 0x2014, 0x2dc,
+    0x2122, 0x161, 0x203a, 0x153, 0x9d, 0x17e, 0x178,
+];
+
+fn encoding_name(encoding: &str) -> String {
+    encoding
+        .to_lowercase()
+        .chars()
+        .filter(|c| *c != '-' && *c != '_')
+        .collect()
+}
+
+// convert csv bytes in utf-8, latin1, windows-1252 or utf-16 to a string
+pub fn decode_text(bytes: &[u8], encoding: &str) -> Result<String, String> {
+    let name = encoding_name(encoding);
+
+    let utf16 = |bytes: &[u8], le: bool| -> Result<String, String> {
+        if bytes.len() & 1 == 1 {
+            return Err("odd number of bytes for utf-16".to_string());
+        }
+        let units: Vec<u16> = bytes
+            .chunks(2)
+            .map(|c| {
+                if le {
+                    u16::from_le_bytes([c[0], c[1]])
+                } else {
+                    u16::from_be_bytes([c[0], c[1]])
+                }
+            })
+            .collect();
+        let s = String::from_utf16(&units).map_err(|e| e.to_string())?;
+        Ok(s.trim_start_matches('\u{feff}').to_string())
+    };
+
+    match name.as_str() {
+        "utf8" => String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string()),
+        "latin1" | "iso88591" => Ok(bytes.iter().map(|b| *b as char).collect()),
+        "windows1252" | "cp1252" => Ok(bytes
+            .iter()
+            .map(|b| match b {
+                0x80..=0x9f => char::from_u32(WINDOWS_1252[(b - 0x80) as usize] as u32).unwrap(),
+                _ => *b as char,
+            })
+            .collect()),
+        "utf16le" => utf16(bytes, true),
+        "utf16be" => utf16(bytes, false),
+        "utf16" => utf16(bytes, !bytes.starts_with(&[0xfe, 0xff])),
+        _ => Err(format!("unknown encoding {}", encoding)),
+    }
+}
+
+// the reverse of decode_text, like is the start of the file being replaced
+// and its utf-16 byte order mark is kept
+pub fn encode_text(text: &str, encoding: &str, like: &[u8]) -> Result<Vec<u8>, String> {
+    let (bom_le, bom_be) = (
+        like.starts_with(&[0xff, 0xfe]),
+        like.starts_with(&[0xfe, 0xff]),
+    );
+    let missing = |c: char| format!("{} cannot be written in {}", c, encoding);
+
+    let utf16 = |le: bool, bom: bool| {
+        let mut out = vec![];
+        for unit in bom.then_some(0xfeff).into_iter().chain(text.encode_utf16()) {
+            if le {
+                out.extend_from_slice(&unit.to_le_bytes());
+            } else {
+                out.extend_from_slice(&unit.to_be_bytes());
+            }
+        }
+        out
+    };
 
-    Some(out_str)
+    match encoding_name(encoding).as_str() {
+        "utf8" => Ok(text.as_bytes().to_vec()),
+        "latin1" | "iso88591" => text
+            .chars()
+            .map(|c| u8::try_from(c).map_err(|_| missing(c)))
+            .collect(),
+        "windows1252" | "cp1252" => text
+            .chars()
+            .map(
+                |c| match WINDOWS_1252.iter().position(|x| *x as u32 == c as u32) {
+                    Some(i) => Ok(0x80 + i as u8),
+                    None => match u8::try_from(c) {
+                        Ok(b) if !(0x80..=0x9f).contains(&b) => Ok(b),
+                        _ => Err(missing(c)),
+                    },
+                },
+            )
+            .collect(),
+        "utf16le" => Ok(utf16(true, bom_le)),
+        "utf16be" => Ok(utf16(false, bom_be)),
+        "utf16" => Ok(utf16(!bom_be, bom_le || bom_be)),
+        _ => Err(format!("unknown encoding {}", encoding)),
+    }
 }
 
 pub fn fd_ready(fd: RawFd) -> bool {
@@ -1381,43 +3796,82 @@ pub fn find_ips<'a>(
     reader: &'a mut Box<dyn BufRead>,
     input_base: Option<i32>,
     reverse: &'a Reverse,
-    config: &'a RefCell<Config>,
+    config: &'a Mutex<Config>,
 ) -> impl 'a + std::iter::Iterator<Item = Vec<Ip>> {
     std::iter::from_fn(move || {
-        if let Some(line) = reader.lines().next().into_iter().by_ref().next() {
-            let line: String = line.as_ref().unwrap().trim().to_string();
-            let mut v = vec![];
-
-            for part in line.split(' ') {
-                let p = part.trim();
-                if p.is_empty() {
-                    continue;
-                }
+        input_line(reader).map(|line| line_ips(&line, input_base, reverse, config))
+    })
+}
 
-                let ip = match parse_address_mask(
-                    p,
-                    Some(32),
-                    Some(128),
-                    input_base,
-                    matches!(reverse, Reverse::Both | Reverse::Input),
-                    config,
-                ) {
-                    Some(x) => x,
-                    None => {
-                        eprintln!("Could not parse {}", p);
-                        continue;
-                    }
-                };
+// the next line of input, lines that are not UTF-8 are skipped
+pub fn input_line(reader: &mut dyn BufRead) -> Option<String> {
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(x) => {
+                eprintln!("Could not read input: {}", x);
+                return None;
+            }
+        }
+        if buf.ends_with(b"\n") {
+            buf.pop();
+        }
+        if buf.ends_with(b"\r") {
+            buf.pop();
+        }
 
-                v.push(ip);
+        match String::from_utf8(buf) {
+            Ok(line) => return Some(line),
+            Err(x) => {
+                eprintln!(
+                    "Could not parse {}, it is not UTF-8",
+                    String::from_utf8_lossy(x.as_bytes())
+                );
+                buf = x.into_bytes();
             }
-            return Some(v);
         }
-        None
-    })
+    }
+}
+
+// the addresses on one line of input
+pub fn line_ips(
+    line: &str,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    config: &Mutex<Config>,
+) -> Vec<Ip> {
+    let mut v = vec![];
+
+    for part in line.trim().split(' ') {
+        let p = part.trim();
+        if p.is_empty() {
+            continue;
+        }
+
+        let ip = match parse_address_mask(
+            p,
+            Some(32),
+            Some(128),
+            input_base,
+            matches!(reverse, Reverse::Both | Reverse::Input),
+            config,
+        ) {
+            Some(x) => x,
+            None => {
+                eprintln!("Could not parse {}", p);
+                continue;
+            }
+        };
+
+        v.push(ip);
+    }
+    v
 }
 
-pub fn subnets_in_network(networks: u32, ip: &Ip) -> u128 {
-    let base: u128 = 2;
-    base.pow(networks - ip.cidr)
+// how many networks of that size are in ip, one when ip is smaller
+pub fn subnets_in_network(networks: u32, ip: &Ip) -> Count {
+    Count::pow2(networks.saturating_sub(ip.cidr))
 }
@@ -1,18 +1,179 @@
 use getopts::Options;
+use nix::fcntl::{Flock, FlockArg};
+use rayon::prelude::*;
 use ripcalc::*;
-use std::cell::RefCell;
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+// results are written through one buffer, flushed when full, at exit, or
+// per line with --line-buffered or on a terminal
+static OUTPUT: LazyLock<Mutex<BufWriter<std::io::Stdout>>> =
+    LazyLock::new(|| Mutex::new(BufWriter::with_capacity(65536, std::io::stdout())));
+static LINE_BUFFERED: AtomicBool = AtomicBool::new(false);
+
+macro_rules! out {
+    ($($arg:tt)*) => {
+        output(format!($($arg)*).as_bytes())
+    };
+}
+
+macro_rules! outln {
+    () => {
+        output(b"\n")
+    };
+    ($($arg:tt)*) => {
+        output(format!("{}\n", format!($($arg)*)).as_bytes())
+    };
+}
+
+fn output(buf: &[u8]) {
+    let mut writer = OUTPUT.lock().unwrap();
+    let mut result = writer.write_all(buf);
+    if result.is_ok() && LINE_BUFFERED.load(Ordering::Relaxed) {
+        result = writer.flush();
+    }
+    if let Err(x) = result {
+        output_failed(x);
+    }
+}
+
+// a reader that has gone away, such as head, is not an error
+fn output_failed(x: std::io::Error) -> ! {
+    if x.kind() == std::io::ErrorKind::BrokenPipe {
+        std::process::exit(0);
+    }
+    eprintln!("Cannot write output: {}", x);
+    std::process::exit(1);
+}
+
+// flush what has been buffered before leaving
+fn exit(code: i32) -> ! {
+    if let Err(x) = OUTPUT.lock().unwrap().flush() {
+        output_failed(x);
+    }
+    std::process::exit(code);
+}
+
+// for writers such as csv that want io::Write
+struct Output;
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        output(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    csv: Option<String>,
+    field: Option<String>,
+    mask: Option<u32>,
+    ipv4_mask: Option<u32>,
+    ipv6_mask: Option<u32>,
+    reverse: Option<String>,
+    base: Option<i32>,
+    formats: HashMap<String, String>,
+}
+
+fn config_path(matches: &getopts::Matches) -> Option<std::path::PathBuf> {
+    if let Some(path) = matches.opt_str("config") {
+        return Some(std::path::PathBuf::from(path));
+    }
+
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(d) if !d.is_empty() => std::path::PathBuf::from(d),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+
+    let path = dir.join("ripcalc").join("config.toml");
+    if path.exists() {
+        return Some(path);
+    }
+    None
+}
+
+fn read_config(matches: &getopts::Matches) -> FileConfig {
+    let path = match config_path(matches) {
+        Some(p) => p,
+        None => return FileConfig::default(),
+    };
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(x) => {
+            eprintln!("Cannot open {}: {}", path.to_string_lossy(), x);
+            exit(1);
+        }
+    };
+
+    match toml::from_str(&contents) {
+        Ok(c) => c,
+        Err(x) => {
+            eprintln!("Cannot read {}: {}", path.to_string_lossy(), x);
+            exit(1);
+        }
+    }
+}
+
+// options from the config file that were not given on the command line
+fn config_args(matches: &getopts::Matches, file_config: &FileConfig) -> Vec<String> {
+    let mut args = vec![];
+    let defaults = [
+        ("csv", file_config.csv.clone()),
+        ("field", file_config.field.clone()),
+        ("mask", file_config.mask.map(|m| m.to_string())),
+        ("reverse", file_config.reverse.clone()),
+        ("base", file_config.base.map(|b| b.to_string())),
+    ];
+
+    // field belongs to the config csv, not to a --csv given instead
+    let config_csv = file_config.csv.is_some() && !matches.opt_present("csv");
+
+    for (name, value) in defaults {
+        if let Some(value) = value {
+            if !matches.opt_present(name) && (name != "field" || config_csv) {
+                args.push(format!("--{}", name));
+                args.push(value);
+            }
+        }
+    }
+
+    args
+}
 
 fn print_details(
     ip: &Ip,
     matches: &getopts::Matches,
-    rows: &Option<HashMap<Ip, NetRow>>,
-    used: Option<&HashMap<Addr, bool>>,
-    config: &RefCell<Config>,
+    rows: &Option<NetRows>,
+    used: Option<&[(Addr, Addr)]>,
+    config: &Mutex<Config>,
+) {
+    emit_details(ip, matches, rows, used, config, &mut |m| out!("{}", m));
+}
+
+// the details of ip passed to emit a result at a time, so that a large list
+// is never held whole
+fn emit_details(
+    ip: &Ip,
+    matches: &getopts::Matches,
+    rows: &Option<NetRows>,
+    used: Option<&[(Addr, Addr)]>,
+    config: &Mutex<Config>,
+    emit: &mut dyn FnMut(&str),
 ) {
     let mut networks: Option<u32> = None;
 
@@ -22,68 +183,75 @@ fn print_details(
         if nets < ip.cidr && !(matches.opt_present("encapsulating") && matches.opt_present("group"))
         {
             eprintln!("{} is bigger than the network mask {}", nets, ip.cidr);
-            std::process::exit(1);
+            exit(1);
         }
 
         match ip.address {
             Addr::V4(_) => {
                 if nets > 32 {
                     eprintln!("{} is too big", nets);
-                    std::process::exit(1);
+                    exit(1);
                 }
             }
             Addr::V6(_) => {
                 if nets > 128 {
                     eprintln!("{} is too big", nets);
-                    std::process::exit(1);
+                    exit(1);
                 }
             }
         }
         networks = Some(nets);
     }
 
-    let mut formatted = if matches.opt_present("f") {
-        matches.opt_str("f").unwrap()
-    } else {
-        let mut network_size = "Network size: %t".to_string();
-        let width = 25;
-        if matches.opt_present("networks") {
-            network_size = format!("Networks ({}): %N", networks.as_ref().unwrap()).to_string();
-        }
-        match ip.address {
+    let formatted = output_format(ip, matches, networks, config);
 
-            Addr::V4(_) => format!("{ip:>width$}/{cidr}\n{broadcast:>width$}\n{network:>width$}\n{subnet:>width$}\n{wildcard:>width$}\n{network_size:>width$}\n", ip="IP is: %a", cidr="%c", broadcast="Broadcast is: %b", network="Network is: %n", subnet="Subnet is: %s", wildcard="Wildcard is: %w", network_size=network_size, width=width),
-            Addr::V6(_) => format!("{ip:>widthn$}/{cidr}\n{expanded:>width$}\n{network:>width$}\n{last_host_address:>width$}\n{subnet:>width$}\n{network_size:>widthn$}\n", ip="IP is: %a", cidr="%c", expanded="Expanded: %xa", network="Network is: %xn", last_host_address="Last host address: %xb", subnet="Subnet is: %xs", network_size=network_size, width=width, widthn=width-1),
+    // the address at --host-index of each network rather than the network
+    let index = matches
+        .opt_str("host-index")
+        .map(|s| match s.trim().parse::<i128>() {
+            Ok(i) => i,
+            Err(_) => {
+                eprintln!("Cannot convert {} to number", s);
+                exit(1);
+            }
+        });
+    let pick = |ip: &Ip| -> Ip {
+        match index {
+            Some(i) => match host_index(ip, i) {
+                Some(host) => host,
+                None => {
+                    eprintln!("{} is outside {}/{}", i, network(ip), ip.cidr);
+                    exit(1);
+                }
+            },
+            None => ip.clone(),
         }
     };
 
-    if formatted == "cidr" {
-        formatted = "%a/%c\n".to_string();
-    }
-
-    if formatted == "short" {
-        formatted = "%a\n".to_string();
-    }
-
     if matches.opt_present("divide") {
         let divide: u32 = match matches.opt_str("divide").unwrap().trim().parse() {
             Ok(x) => x,
             Err(x) => {
                 eprintln!("Cannot convert {} to number", x);
-                std::process::exit(1);
+                exit(1);
             }
         };
 
-        for ip_copy in addresses(ip, used, Some(divide)) {
+        if divide > ip.address.width() {
+            eprintln!("{} is too big", divide);
+            exit(1);
+        }
+
+        for ip_copy in walk_addresses(ip, used, Some(divide), &walk_opts(matches)) {
             if let Some(m) = format_details(
-                &ip_copy,
+                &pick(&ip_copy),
                 formatted.to_string(),
                 rows,
                 networks,
                 Some(matches),
                 config,
             ) {
-                print!("{}", m);
+                emit(&m);
             }
         }
         return;
@@ -92,12 +260,12 @@ fn print_details(
     if matches.opt_present("list") {
         if matches.opt_present("noexpand") {
             if let Some(m) = format_details(ip, formatted, rows, networks, Some(matches), config) {
-                print!("{}", m);
+                emit(&m);
             }
             return;
         }
 
-        for ip_copy in addresses(ip, used, None) {
+        for ip_copy in walk_addresses(ip, used, None, &walk_opts(matches)) {
             if let Some(m) = format_details(
                 &ip_copy,
                 formatted.to_string(),
@@ -106,14 +274,79 @@ fn print_details(
                 Some(matches),
                 config,
             ) {
-                print!("{}", m);
+                emit(&m);
             }
         }
         return;
     }
 
-    if let Some(m) = format_details(ip, formatted, rows, networks, Some(matches), config) {
-        print!("{}", m);
+    if let Some(m) = format_details(&pick(ip), formatted, rows, networks, Some(matches), config) {
+        emit(&m);
+    }
+}
+
+fn output_format(
+    ip: &Ip,
+    matches: &getopts::Matches,
+    networks: Option<u32>,
+    config: &Mutex<Config>,
+) -> String {
+    let mut formatted = if matches.opt_present("f") {
+        matches.opt_str("f").unwrap()
+    } else {
+        let mut network_size = "Network size: %t".to_string();
+        let width = 25;
+        if let Some(networks) = networks {
+            network_size = format!("Networks ({}): %N", networks).to_string();
+        }
+        match ip.address {
+
+            Addr::V4(_) => format!("{ip:>width$}/{cidr}\n{broadcast:>width$}\n{network:>width$}\n{subnet:>width$}\n{wildcard:>width$}\n{network_size:>width$}\n", ip="IP is: %a", cidr="%c", broadcast="Broadcast is: %b", network="Network is: %n", subnet="Subnet is: %s", wildcard="Wildcard is: %w", network_size=network_size, width=width),
+            Addr::V6(_) => format!("{ip:>widthn$}/{cidr}\n{expanded:>width$}\n{network:>width$}\n{last_host_address:>width$}\n{subnet:>width$}\n{network_size:>widthn$}\n", ip="IP is: %a", cidr="%c", expanded="Expanded: %xa", network="Network is: %xn", last_host_address="Last host address: %xb", subnet="Subnet is: %xs", network_size=network_size, width=width, widthn=width-1),
+        }
+    };
+
+    if formatted == "cidr" {
+        formatted = "%a/%c\n".to_string();
+    }
+
+    if formatted == "short" {
+        formatted = "%a\n".to_string();
+    }
+
+    if let Some(f) = config.lock().unwrap().formats.get(&formatted) {
+        formatted = f.to_string();
+    }
+
+    formatted
+}
+
+fn explain_format(
+    ip: &Ip,
+    matches: &getopts::Matches,
+    rows: &Option<NetRows>,
+    config: &Mutex<Config>,
+) {
+    let networks: Option<u32> = matches
+        .opt_str("networks")
+        .map(|n| n.trim().parse().unwrap());
+    let formatted = output_format(ip, matches, networks, config);
+
+    outln!("Format tokens rendered for {}/{}:", ip, ip.cidr);
+    for token in format_tokens(&formatted) {
+        // each token is rendered as the first result
+        config.lock().unwrap().output_index = 0;
+        let sample = match format_details(ip, token.source(), rows, networks, Some(matches), config)
+        {
+            Some(s) => format!("{:?}", s),
+            None => "(no csv match)".to_string(),
+        };
+        outln!(
+            "{:<12} {:<48} {}",
+            format!("{:?}", token.source()),
+            token.description(),
+            sample
+        );
     }
 }
 
@@ -126,146 +359,1815 @@ fn banner() -> String {
 }
 
 fn print_version() {
-    println!("{}", &banner());
+    outln!("{}", &banner());
 }
 
-fn process_csv(
-    mut reader: csv::Reader<File>,
-    field_name: String,
-    rows: &mut Option<HashMap<Ip, NetRow>>,
-    input_base: Option<i32>,
-    reverse: &Reverse,
-) {
-    let headers = reader.headers().unwrap();
-    let mut header_names: Vec<String> = vec![];
-    let mut field_num: Option<usize> = None;
-    for i in 0..headers.len() {
-        if headers[i] == field_name {
-            field_num = Some(i);
+// --csv takes an optional alias, site=path/to/site.csv, the file stem otherwise
+fn csv_alias(arg: &str) -> (String, String) {
+    if let Some((alias, path)) = arg.split_once('=') {
+        if !alias.is_empty()
+            && alias
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        {
+            return (alias.to_string(), path.to_string());
+        }
+    }
+
+    let alias = std::path::Path::new(arg)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| arg.to_string());
+    (alias, arg.to_string())
+}
+
+// a single byte csv option, tab can be given as \t
+fn csv_byte(name: &str, matches: &getopts::Matches) -> Option<u8> {
+    let value = matches.opt_str(name)?;
+    let value = match value.as_str() {
+        "\\t" | "tab" => "\t".to_string(),
+        _ => value,
+    };
+
+    if value.len() != 1 {
+        eprintln!("--{} must be a single character", name);
+        exit(1);
+    }
+    Some(value.as_bytes()[0])
+}
+
+fn csv_reader(path: &str, matches: &getopts::Matches) -> csv::Reader<Box<dyn Read>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(x) => {
+            eprintln!("Cannot open {}: {}", path, x);
+            exit(1);
         }
-        header_names.push(headers[i].to_string());
+    };
+
+    let input: Box<dyn Read> = match matches.opt_str("csv-encoding") {
+        Some(_) => Box::new(std::io::Cursor::new(csv_text(path, file, matches))),
+        None => Box::new(file),
+    };
+
+    let mut builder = csv_dialect(matches);
+    builder.has_headers(!matches.opt_present("csv-noheader"));
+    builder.comment(csv_byte("csv-comment", matches));
+    if matches.opt_present("csv-trim") {
+        builder.trim(csv::Trim::All);
+    }
+
+    builder.from_reader(input)
+}
+
+fn csv_dialect(matches: &getopts::Matches) -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    if let Some(d) = csv_byte("csv-delimiter", matches) {
+        builder.delimiter(d);
     }
-    if field_num.is_none() {
-        eprintln!("Cannot find csv field {}", field_name);
-        std::process::exit(1);
+    if let Some(q) = csv_byte("csv-quote", matches) {
+        builder.quote(q);
     }
+    builder
+}
 
-    let field_num = field_num.unwrap();
+// the contents of file as utf-8, decoded from --csv-encoding
+fn csv_text(path: &str, file: File, matches: &getopts::Matches) -> Vec<u8> {
+    let mut bytes = vec![];
+    if let Err(x) = BufReader::new(file).read_to_end(&mut bytes) {
+        eprintln!("Cannot read {}: {}", path, x);
+        exit(1);
+    }
+    match matches.opt_str("csv-encoding") {
+        Some(encoding) => match decode_text(&bytes, &encoding) {
+            Ok(text) => text.into_bytes(),
+            Err(x) => {
+                eprintln!("Cannot decode {}: {}", path, x);
+                exit(1);
+            }
+        },
+        None => bytes,
+    }
+}
 
-    for result in reader.records() {
-        let record = result.unwrap();
+// rewriting would lose the lines --csv-comment skips, so a file with any is
+// left as it is
+fn refuse_comments(path: &str, matches: &getopts::Matches) {
+    let comment = match csv_byte("csv-comment", matches) {
+        Some(c) => c,
+        None => return,
+    };
+    let text = match File::open(path) {
+        Ok(f) => csv_text(path, f, matches),
+        Err(x) => {
+            eprintln!("Cannot open {}: {}", path, x);
+            exit(1);
+        }
+    };
 
-        if record.get(field_num).is_some() {
-            let rec = record.get(field_num).unwrap();
-            let parts: Vec<&str> = rec.split('/').collect();
-            if parts.len() < 2 {
-                eprintln!("{}: not in ip/cidr format", rec);
-                continue;
+    // read without comments, a record that starts with the comment byte is
+    // one the csv reader skips
+    let mut reader = csv_dialect(matches)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(&text[..]);
+    let mut record = csv::ByteRecord::new();
+    let mut lines = vec![];
+    while let Ok(true) = reader.read_byte_record(&mut record) {
+        if let Some(p) = record.position() {
+            if text.get(p.byte() as usize) == Some(&comment) {
+                lines.push(p.line());
             }
+        }
+    }
 
-            let mut row_ip: Option<Addr> = None;
+    for line in &lines {
+        eprintln!("{}:{}: comment would be lost", path, line);
+    }
+    if !lines.is_empty() {
+        eprintln!("{} is not rewritten, it has comments", path);
+        exit(1);
+    }
+}
 
-            if parts[0].contains(':') {
-                if rows.is_none() {
-                    *rows = Some(HashMap::new());
-                }
+// the header names and the columns holding the network or range
+fn csv_columns(
+    reader: &mut csv::Reader<Box<dyn Read>>,
+    field_name: &str,
+) -> (Vec<String>, Vec<usize>) {
+    let headers = match reader.headers() {
+        Ok(h) => h.clone(),
+        Err(x) => {
+            eprintln!("Cannot read csv header: {}", x);
+            exit(1);
+        }
+    };
+    let mut header_names: Vec<String> = vec![];
+    for i in 0..headers.len() {
+        // without a header row fields are numbered from 1
+        let name = if reader.has_headers() {
+            headers[i].to_string()
+        } else {
+            (i + 1).to_string()
+        };
+        header_names.push(name);
+    }
 
-                let v6 = parse_v6(
-                    parts[0],
-                    input_base,
-                    matches!(reverse, Reverse::Both | Reverse::Source),
-                );
+    // start,end names two columns holding a range
+    let mut field_nums: Vec<usize> = vec![];
+    for f in field_name.split(',') {
+        match header_names.iter().position(|h| h == f) {
+            Some(i) => field_nums.push(i),
+            None => {
+                eprintln!("Cannot find csv field {}", f);
+                exit(1);
+            }
+        }
+    }
+    if field_nums.len() > 2 {
+        eprintln!("Too many csv fields in {}", field_name);
+        exit(1);
+    }
 
-                if v6.is_none() {
-                    eprintln!("{}: not in ip/cidr format", rec);
-                    continue;
-                }
-                row_ip = v6;
+    (header_names, field_nums)
+}
+
+// the network or range of a csv row, as it is written
+enum CsvKey {
+    Network(Ip),
+    Range(Addr, Addr),
+}
+
+// Err(None) when the row is too short to have the fields
+fn csv_key(
+    record: &csv::StringRecord,
+    field_nums: &[usize],
+    input_base: Option<i32>,
+    source: bool,
+) -> Result<CsvKey, Option<String>> {
+    if field_nums.iter().any(|i| record.get(*i).is_none()) {
+        return Err(None);
+    }
+
+    let rec = field_nums
+        .iter()
+        .map(|i| &record[*i])
+        .collect::<Vec<&str>>()
+        .join("-");
+
+    if field_nums.len() == 1 && rec.contains('/') {
+        let parts: Vec<&str> = rec.split('/').collect();
+        let cidr = parts[1].parse::<u32>();
+        let v6 = matches!(cidr, Ok(c) if c > 32);
+        let address = parse_csv_address(parts[0], input_base, source, v6);
+        return match (address, cidr) {
+            (Some(a), Ok(c)) if parts.len() == 2 && c <= a.width() => Ok(CsvKey::Network(Ip {
+                address: a,
+                cidr: c,
+            })),
+            (Some(_), Ok(_)) if parts.len() == 2 => {
+                Err(Some(format!("{}: mask out of range", rec)))
             }
+            _ => Err(Some(format!("{}: not in ip/cidr or range format", rec))),
+        };
+    }
 
-            if parts[0].contains('.') {
-                if rows.is_none() {
-                    *rows = Some(HashMap::new());
-                }
+    let parts: Vec<&str> = if field_nums.len() == 2 {
+        field_nums.iter().map(|i| &record[*i]).collect()
+    } else {
+        rec.split('-').collect()
+    };
+    let range = if parts.len() == 2 {
+        let address = |s: &str, v6| parse_csv_address(s.trim(), input_base, source, v6);
+        // an integer bound is V6 when the other bound is
+        let v6 = parts
+            .iter()
+            .any(|p| matches!(address(p, false), Some(Addr::V6(_))));
+        (address(parts[0], v6), address(parts[1], v6))
+    } else {
+        (None, None)
+    };
+    match range {
+        (Some(s), Some(e)) if s.same_family(&e) && s <= e => Ok(CsvKey::Range(s, e)),
+        _ => Err(Some(format!("{}: not in ip/cidr or range format", rec))),
+    }
+}
 
-                let v4 = parse_v4(
-                    parts[0],
-                    input_base,
-                    matches!(reverse, Reverse::Both | Reverse::Source),
-                );
+type CsvOrder = (bool, u128, std::cmp::Reverse<u128>);
 
-                if v4.is_none() {
-                    eprintln!("{}: not in ip/cidr format", rec);
-                    continue;
+// csv rows in numeric order, v4 first, larger rows before those inside them
+fn csv_order(start: &Addr, end: &Addr) -> CsvOrder {
+    (
+        matches!(start, Addr::V6(_)),
+        start.to_u128(),
+        std::cmp::Reverse(end.to_u128()),
+    )
+}
+
+// report problems with the rows of a csv, returning how many were found
+fn check_csv(
+    path: &str,
+    field_name: &str,
+    matches: &getopts::Matches,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+) -> usize {
+    let mut reader = csv_reader(path, matches);
+    let (header_names, field_nums) = csv_columns(&mut reader, field_name);
+    let source = matches!(reverse, Reverse::Both | Reverse::Source);
+    let names: Vec<&String> = header_names
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !field_nums.contains(i))
+        .map(|(_, h)| h)
+        .collect();
+
+    let mut problems = 0;
+    // start, end, line, network or range and the other fields
+    let mut entries: Vec<(Addr, Addr, u64, String, Vec<String>)> = vec![];
+
+    for result in reader.records() {
+        let record = match result {
+            Ok(r) => r,
+            Err(x) => {
+                outln!("{}: {}", path, x);
+                problems += 1;
+                continue;
+            }
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+
+        let (start, end, key) = match csv_key(&record, &field_nums, input_base, source) {
+            Ok(CsvKey::Network(ip)) => {
+                let n = network(&ip);
+                let key = format!("{}/{}", ip, ip.cidr);
+                if n.address != ip.address {
+                    outln!(
+                        "{}:{}: {}: not a network, should be {}/{}",
+                        path,
+                        line,
+                        key,
+                        n,
+                        n.cidr
+                    );
+                    problems += 1;
                 }
-                row_ip = v4;
+                (n.address, broadcast(&ip).address, key)
             }
-
-            let cidr = parts[1].to_string().parse::<u32>();
-            if cidr.is_err() {
-                eprintln!("{}: not in ip/cidr format", rec);
+            Ok(CsvKey::Range(start, end)) => {
+                let key = format!("{}-{}", start, end);
+                (start, end, key)
+            }
+            Err(None) => {
+                outln!("{}:{}: missing {}", path, line, field_name);
+                problems += 1;
                 continue;
             }
-            let cidr = cidr.unwrap();
-
-            if row_ip.is_none() {
+            Err(Some(x)) => {
+                outln!("{}:{}: {}", path, line, x);
+                problems += 1;
                 continue;
             }
+        };
 
-            let ip = Ip {
-                address: row_ip.unwrap(),
-                cidr,
-            };
+        let fields: Vec<String> = record
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !field_nums.contains(i))
+            .map(|(_, v)| v.to_string())
+            .collect();
+        entries.push((start, end, line, key, fields));
+    }
 
-            let mut hm = HashMap::new();
-            for i in 0..header_names.len() {
-                hm.insert(header_names[i].to_string(), record[i].to_string());
-            }
+    entries.sort_by_key(|e| (csv_order(&e.0, &e.1), e.2));
+
+    // rows that reach the start of this one, those ending before its end
+    // overlap it and the last of the others holds it
+    let mut open: Vec<usize> = vec![];
+    for i in 0..entries.len() {
+        let (start, end, line, key, fields) = &entries[i];
+        open.retain(|&j| entries[j].1.same_family(start) && entries[j].1 >= *start);
+
+        if let Some(&j) = open.iter().rev().find(|&&j| entries[j].1 < *end) {
+            outln!(
+                "{}:{}: {}: overlaps {} on line {}",
+                path,
+                line,
+                key,
+                entries[j].3,
+                entries[j].2
+            );
+            problems += 1;
+        }
 
-            rows.as_mut()
-                .unwrap()
-                .insert(ip.clone(), NetRow { row: hm.clone() });
+        if let Some(&j) = open.iter().rev().find(|&&j| entries[j].1 >= *end) {
+            let (p_start, p_end, p_line, p_key, p_fields) = &entries[j];
+            if p_start == start && p_end == end {
+                if p_fields == fields {
+                    outln!("{}:{}: {}: duplicate of line {}", path, line, key, p_line);
+                } else {
+                    outln!(
+                        "{}:{}: {}: duplicate of line {} with different fields",
+                        path,
+                        line,
+                        key,
+                        p_line
+                    );
+                }
+                problems += 1;
+            } else if p_fields == fields {
+                outln!(
+                    "{}:{}: {}: inside {} on line {} with the same fields",
+                    path,
+                    line,
+                    key,
+                    p_key,
+                    p_line
+                );
+                problems += 1;
+            } else if let Some(name) = names
+                .iter()
+                .zip(p_fields.iter().zip(fields))
+                .find(|(_, (p, v))| !p.is_empty() && !v.is_empty() && p != v)
+                .map(|x| x.0)
+            {
+                // both rows match the addresses of the inner one
+                outln!(
+                    "{}:{}: {}: inside {} on line {} with a different {}",
+                    path,
+                    line,
+                    key,
+                    p_key,
+                    p_line,
+                    name
+                );
+                problems += 1;
+            }
         }
+        open.push(i);
     }
+
+    problems
 }
 
-#[allow(clippy::too_many_arguments)]
-fn process_input_file(
+// rewrite a csv in numeric order with networks on their boundaries
+fn canonicalise_csv(
     path: &str,
+    field_name: &str,
     matches: &getopts::Matches,
     input_base: Option<i32>,
     reverse: &Reverse,
-    ip_args: &[Ip],
-    rows: &Option<HashMap<Ip, NetRow>>,
-    inside: Option<bool>,
-    config: &RefCell<Config>,
 ) {
-    let mut reader: Box<dyn BufRead> = if path == "-" {
-        Box::new(BufReader::new(std::io::stdin()))
-    } else {
-        let path = std::path::Path::new(&path);
-        if !path.exists() {
-            println!(
-                "Could not open {} as it does not exist",
-                path.to_string_lossy()
-            );
-            std::process::exit(1);
+    refuse_comments(path, matches);
+    let mut reader = csv_reader(path, matches);
+    let (_, field_nums) = csv_columns(&mut reader, field_name);
+    let source = matches!(reverse, Reverse::Both | Reverse::Source);
+
+    let headers = reader.headers().ok().cloned();
+    let mut keyed: Vec<(CsvOrder, csv::StringRecord)> = vec![];
+    // rows that cannot be read are kept, at the end
+    let mut unkeyed: Vec<csv::StringRecord> = vec![];
+
+    for result in reader.records() {
+        let record = match result {
+            Ok(r) => r,
+            Err(x) => {
+                eprintln!("Cannot read {}: {}", path, x);
+                exit(1);
+            }
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or_default();
+
+        let (start, end, cells) = match csv_key(&record, &field_nums, input_base, source) {
+            Ok(CsvKey::Network(ip)) => {
+                let n = network(&ip);
+                let cell = format!("{}/{}", n, n.cidr);
+                (n.address, broadcast(&ip).address, vec![cell])
+            }
+            Ok(CsvKey::Range(start, end)) => {
+                let cells = if field_nums.len() == 2 {
+                    vec![start.to_string(), end.to_string()]
+                } else {
+                    vec![format!("{}-{}", start, end)]
+                };
+                (start, end, cells)
+            }
+            Err(x) => {
+                eprintln!(
+                    "{}:{}: {}, kept as it is",
+                    path,
+                    line,
+                    x.unwrap_or_else(|| format!("missing {}", field_name))
+                );
+                unkeyed.push(record);
+                continue;
+            }
+        };
+
+        let mut canonical = csv::StringRecord::new();
+        for (i, value) in record.iter().enumerate() {
+            match field_nums.iter().position(|f| *f == i) {
+                Some(c) => canonical.push_field(&cells[c]),
+                None => canonical.push_field(value),
+            }
+        }
+        keyed.push((csv_order(&start, &end), canonical));
+    }
+    keyed.sort_by_key(|k| k.0);
+
+    let mut records = vec![];
+    if let (Some(h), true) = (headers, !matches.opt_present("csv-noheader")) {
+        records.push(h);
+    }
+    records.extend(keyed.into_iter().map(|(_, r)| r));
+    records.extend(unkeyed);
+
+    write_csv(path, &records, matches);
+}
+
+// written in --csv-encoding beside the original then renamed over it, so
+// readers never see part of a file
+fn write_csv(path: &str, records: &[csv::StringRecord], matches: &getopts::Matches) {
+    let mut builder = csv::WriterBuilder::new();
+    builder.flexible(true);
+    if let Some(d) = csv_byte("csv-delimiter", matches) {
+        builder.delimiter(d);
+    }
+    if let Some(q) = csv_byte("csv-quote", matches) {
+        builder.quote(q);
+    }
+    let mut writer = builder.from_writer(vec![]);
+    for record in records {
+        if let Err(x) = writer.write_record(record) {
+            eprintln!("Cannot write {}: {}", path, x);
+            exit(1);
+        }
+    }
+    let mut bytes = match writer.into_inner() {
+        Ok(b) => b,
+        Err(x) => {
+            eprintln!("Cannot write {}: {}", path, x);
+            exit(1);
         }
-        Box::new(BufReader::new(File::open(path).unwrap()))
     };
 
+    if let Some(encoding) = matches.opt_str("csv-encoding") {
+        let mut like = vec![];
+        if let Ok(f) = File::open(path) {
+            let _ = f.take(2).read_to_end(&mut like);
+        }
+        let text = String::from_utf8(bytes).unwrap();
+        bytes = match encode_text(&text, &encoding, &like) {
+            Ok(b) => b,
+            Err(x) => {
+                eprintln!("Cannot write {}: {}", path, x);
+                exit(1);
+            }
+        };
+    }
+
+    let tmp = format!("{}.{}.tmp", path, std::process::id());
+    let written = File::create(&tmp).and_then(|mut f| {
+        f.write_all(&bytes)?;
+        f.sync_all()
+    });
+    if let Err(x) = written {
+        eprintln!("Cannot write {}: {}", tmp, x);
+        let _ = std::fs::remove_file(&tmp);
+        exit(1);
+    }
+
+    if let Err(x) = std::fs::rename(&tmp, path) {
+        eprintln!("Cannot replace {}: {}", path, x);
+        exit(1);
+    }
+}
+
+// the ledger is the first --csv, with its network in the first --field
+fn ledger_path(matches: &getopts::Matches) -> (String, String) {
+    let path = match matches.opt_strs("csv").first() {
+        Some(arg) => csv_alias(arg).1,
+        None => {
+            eprintln!("--allocate and --release need a --csv ledger");
+            exit(1);
+        }
+    };
+    let field_name = match matches.opt_strs("field").first() {
+        Some(f) => f.to_string(),
+        None if matches.opt_present("csv-noheader") => "1".to_string(),
+        None => "network".to_string(),
+    };
+    (path, field_name)
+}
+
+// the fields of the ledger, a new ledger has the network field and the --set
+// fields
+fn ledger_fields(path: &str, field_name: &str, matches: &getopts::Matches) -> Vec<String> {
+    if std::fs::metadata(path).map(|m| m.len()).unwrap_or_default() > 0 {
+        refuse_comments(path, matches);
+        return csv_columns(&mut csv_reader(path, matches), field_name).0;
+    }
+    let mut header_names: Vec<String> = field_name.split(',').map(|f| f.to_string()).collect();
+    for set in matches.opt_strs("set") {
+        if let Some((f, _)) = set.split_once('=') {
+            if !header_names.iter().any(|h| h == f) {
+                header_names.push(f.to_string());
+            }
+        }
+    }
+    header_names
+}
+
+// allocate or release networks in the first --csv, locking the ledger while
+// it is read and written
+fn ledger(
+    matches: &getopts::Matches,
+    ip_args: &[Ip],
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    config: &Mutex<Config>,
+) {
+    let (path, field_name) = ledger_path(matches);
+    let noheader = matches.opt_present("csv-noheader");
+    let source = matches!(reverse, Reverse::Both | Reverse::Source);
+
+    if ip_args.is_empty() {
+        eprintln!("--allocate needs a pool network and --release a network");
+        exit(1);
+    }
+
+    // the ledger is replaced by rename, a lock taken on one that has since
+    // been replaced is let go and taken on the new file
+    let lock = loop {
+        let file = match std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+        {
+            Ok(f) => f,
+            Err(x) => {
+                eprintln!("Cannot open {}: {}", path, x);
+                exit(1);
+            }
+        };
+        let lock = match Flock::lock(file, FlockArg::LockExclusive) {
+            Ok(l) => l,
+            Err((_, x)) => {
+                eprintln!("Cannot lock {}: {}", path, x);
+                exit(1);
+            }
+        };
+        match (lock.metadata(), std::fs::metadata(&path)) {
+            (Ok(a), Ok(b)) if a.dev() == b.dev() && a.ino() == b.ino() => break lock,
+            _ => continue,
+        }
+    };
+
+    // a new ledger has the network field and the --set fields
+    let mut header_names: Vec<String> = field_name.split(',').map(|f| f.to_string()).collect();
+    let mut field_nums: Vec<usize> = (0..header_names.len()).collect();
+    let mut records: Vec<csv::StringRecord> = vec![];
+    // a ledger that is empty was created by locking it
+    if lock.metadata().map(|m| m.len()).unwrap_or_default() > 0 {
+        refuse_comments(&path, matches);
+        let mut reader = csv_reader(&path, matches);
+        (header_names, field_nums) = csv_columns(&mut reader, &field_name);
+        for result in reader.records() {
+            match result {
+                Ok(r) => records.push(r),
+                Err(x) => {
+                    eprintln!("Cannot read {}: {}", path, x);
+                    exit(1);
+                }
+            }
+        }
+    } else {
+        header_names = ledger_fields(&path, &field_name, matches);
+    }
+
+    let key_of = |r: &csv::StringRecord| match csv_key(r, &field_nums, input_base, source) {
+        Ok(CsvKey::Network(ip)) => Some((network(&ip).address, broadcast(&ip).address)),
+        Ok(CsvKey::Range(start, end)) => Some((start, end)),
+        Err(_) => None,
+    };
+
+    let formatted = if matches.opt_present("format") {
+        output_format(&ip_args[0], matches, None, config)
+    } else {
+        "%a/%c\n".to_string()
+    };
+
+    let row_of = |record: &csv::StringRecord| NetRow {
+        row: header_names
+            .iter()
+            .zip(record.iter())
+            .map(|(h, v)| (h.to_string(), v.to_string()))
+            .collect(),
+    };
+
+    let mut output = vec![];
+    if matches.opt_present("release") {
+        for arg in ip_args {
+            let (start, end) = (network(arg).address, broadcast(arg).address);
+            let before = records.len();
+            let mut kept = vec![];
+            for record in records {
+                if key_of(&record) == Some((start.clone(), end.clone())) {
+                    let m = RowMatch {
+                        start: start.clone(),
+                        end: end.clone(),
+                        network: Some(network(arg)),
+                        row: &row_of(&record),
+                    };
+                    output.push(format_row(&m, &formatted, None, config));
+                } else {
+                    kept.push(record);
+                }
+            }
+            records = kept;
+            if records.len() == before {
+                eprintln!("{}/{} is not allocated in {}", arg, arg.cidr, path);
+                exit(1);
+            }
+        }
+    } else {
+        let cidr = match matches.opt_str("allocate").as_deref().and_then(parse_mask) {
+            Some(c) => c,
+            None => {
+                eprintln!("Invalid --allocate size");
+                exit(1);
+            }
+        };
+        let align = match matches.opt_str("align") {
+            Some(a) => match parse_mask(&a) {
+                Some(a) => Some(a),
+                None => {
+                    eprintln!("Invalid --align {}", a);
+                    exit(1);
+                }
+            },
+            None => None,
+        };
+        let fit = match matches.opt_str("fit").as_deref() {
+            None | Some("first") => Fit::First,
+            Some("best") => Fit::Best,
+            Some(f) => {
+                eprintln!("Invalid --fit {}, use first or best", f);
+                exit(1);
+            }
+        };
+
+        // space under a row that cannot be read may be in use
+        let mut unreadable = 0;
+        for record in &records {
+            if let Err(x) = csv_key(record, &field_nums, input_base, source) {
+                let line = record.position().map(|p| p.line()).unwrap_or_default();
+                let x = x.unwrap_or_else(|| format!("missing {}", field_name));
+                eprintln!("{}:{}: {}", path, line, x);
+                unreadable += 1;
+            }
+        }
+        if unreadable > 0 {
+            eprintln!(
+                "Nothing is allocated from {} until these rows are fixed",
+                path
+            );
+            exit(1);
+        }
+
+        let used: Vec<(Addr, Addr)> = records.iter().filter_map(key_of).collect();
+        let ip = match ip_args
+            .iter()
+            .find_map(|pool| allocate(pool, used.clone(), cidr, align, &fit))
+        {
+            Some(ip) => ip,
+            None => {
+                eprintln!("No free /{} in {}", cidr, path);
+                exit(1);
+            }
+        };
+
+        let mut cells = vec!["".to_string(); header_names.len()];
+        if field_nums.len() == 2 {
+            cells[field_nums[0]] = ip.address.to_string();
+            cells[field_nums[1]] = broadcast(&ip).address.to_string();
+        } else {
+            cells[field_nums[0]] = format!("{}/{}", ip, ip.cidr);
+        }
+        for set in matches.opt_strs("set") {
+            let (f, v) = match set.split_once('=') {
+                Some(x) => x,
+                None => {
+                    eprintln!("{}: not in field=value format", set);
+                    exit(1);
+                }
+            };
+            match header_names.iter().position(|h| h == f) {
+                Some(i) if !field_nums.contains(&i) => cells[i] = v.to_string(),
+                _ => {
+                    eprintln!("{}: {} is not a csv field", set, f);
+                    exit(1);
+                }
+            }
+        }
+
+        let record = csv::StringRecord::from(cells);
+        let m = RowMatch {
+            start: ip.address.clone(),
+            end: broadcast(&ip).address,
+            network: Some(ip.clone()),
+            row: &row_of(&record),
+        };
+        output.push(format_row(&m, &formatted, None, config));
+        records.push(record);
+    }
+
+    if !noheader {
+        records.insert(0, csv::StringRecord::from(header_names.clone()));
+    }
+    write_csv(&path, &records, matches);
+
+    for o in output {
+        out!("{}", o);
+    }
+    exit(0);
+}
+
+// the options that shape the rows of an index, one compiled with others is stale
+fn index_key(matches: &getopts::Matches) -> String {
+    let mut key = vec![];
+    for name in [
+        "csv",
+        "field",
+        "csv-delimiter",
+        "csv-quote",
+        "csv-comment",
+        "csv-encoding",
+        "base",
+        "reverse",
+    ] {
+        key.push(format!("{}={}", name, matches.opt_strs(name).join(",")));
+    }
+    for name in ["csv-noheader", "csv-trim"] {
+        key.push(format!("{}={}", name, matches.opt_present(name)));
+    }
+    key.join("\n")
+}
+
+// the index at path, unless it cannot be read or the csv has moved on
+fn fresh_index(path: &str, key: &str) -> Option<CsvIndex> {
+    let index = match CsvIndex::open(path) {
+        Ok(x) => x,
+        Err(x) => {
+            eprintln!("{}, reading the csv", x);
+            return None;
+        }
+    };
+
+    if let Some(reason) = index.stale(key) {
+        eprintln!("{} is stale, {}, reading the csv", path, reason);
+        return None;
+    }
+    Some(index)
+}
+
+// written beside the old index then renamed over it, as the old one may be
+// mapped by another ripcalc
+fn write_index(
+    matches: &getopts::Matches,
+    rows: &Option<NetRows>,
+    fields: &Option<Vec<String>>,
+    sources: &[IndexSource],
+    key: &str,
+) {
+    let path = match matches.opt_str("index") {
+        Some(p) if matches.opt_present("csv") => p,
+        _ => {
+            eprintln!("--compile-index needs --csv and --index");
+            exit(1);
+        }
+    };
+
+    let empty = NetRows::default();
+    let bytes = compile_index(
+        rows.as_ref().unwrap_or(&empty),
+        fields.as_deref().unwrap_or_default(),
+        sources,
+        key,
+    );
+
+    let tmp = format!("{}.{}.tmp", path, std::process::id());
+    let written = File::create(&tmp).and_then(|mut f| {
+        f.write_all(&bytes)?;
+        f.sync_all()
+    });
+    if let Err(x) = written {
+        eprintln!("Cannot write {}: {}", tmp, x);
+        exit(1);
+    }
+
+    if let Err(x) = std::fs::rename(&tmp, &path) {
+        eprintln!("Cannot replace {}: {}", path, x);
+        exit(1);
+    }
+}
+
+fn process_csv(
+    mut reader: csv::Reader<Box<dyn Read>>,
+    alias: &str,
+    field_name: String,
+    rows: &mut Option<NetRows>,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+) -> Vec<String> {
+    let (header_names, field_nums) = csv_columns(&mut reader, &field_name);
+    let source = matches!(reverse, Reverse::Both | Reverse::Source);
+
+    for result in reader.records() {
+        let record = match result {
+            Ok(r) => r,
+            Err(x) => {
+                eprintln!("{}", x);
+                continue;
+            }
+        };
+
+        let (start, end) = match csv_key(&record, &field_nums, input_base, source) {
+            Ok(CsvKey::Network(ip)) => (network(&ip).address, broadcast(&ip).address),
+            Ok(CsvKey::Range(start, end)) => (start, end),
+            Err(None) => continue,
+            Err(Some(x)) => {
+                eprintln!("{}", x);
+                continue;
+            }
+        };
+
+        let net_rows = rows.get_or_insert_with(NetRows::default);
+
+        // ranges that fall on a network boundary are kept as networks
+        let net_row = match range_network(&start, &end) {
+            Some(ip) => {
+                // later files take precedence, but fields they lack are kept
+                net_rows.networks.entry(ip).or_insert(NetRow {
+                    row: HashMap::new(),
+                })
+            }
+            None => {
+                net_rows.ranges.push(NetRange {
+                    start,
+                    end,
+                    row: NetRow {
+                        row: HashMap::new(),
+                    },
+                });
+                &mut net_rows.ranges.last_mut().unwrap().row
+            }
+        };
+
+        // the fields of the file that gave the row before move to alias.field
+        let sources: Vec<String> = net_row.sources().iter().map(|s| s.to_string()).collect();
+        if let Some(previous) = sources.first().filter(|s| *s != alias) {
+            let plain: Vec<String> = net_row
+                .row
+                .keys()
+                .filter(|k| {
+                    *k != "_source"
+                        && *k != "_sources"
+                        && !sources[1..]
+                            .iter()
+                            .any(|s| k.starts_with(&format!("{}.", s)))
+                })
+                .cloned()
+                .collect();
+            for k in plain {
+                let v = net_row.row.remove(&k).unwrap();
+                net_row.row.insert(format!("{}.{}", previous, k), v);
+            }
+            let mut all = vec![alias.to_string()];
+            all.extend(sources);
+            net_row.row.insert("_sources".to_string(), all.join(" "));
+        }
+
+        for (name, value) in header_names.iter().zip(record.iter()) {
+            net_row.row.insert(name.to_string(), value.to_string());
+        }
+        net_row.row.insert("_source".to_string(), alias.to_string());
+    }
+
+    let mut fields = header_names.clone();
+    for h in &header_names {
+        fields.push(format!("{}.{}", alias, h));
+    }
+    fields.push("_source".to_string());
+    fields.push("_sources".to_string());
+    fields
+}
+
+// list csv rows matching --query, exit 1 when there are none
+fn query(
+    matches: &getopts::Matches,
+    rows: &Option<NetRows>,
+    fields: Option<&[String]>,
+    config: &Mutex<Config>,
+) {
+    let rows = match rows {
+        Some(r) => r,
+        None => {
+            eprintln!("--query needs a --csv");
+            exit(1);
+        }
+    };
+
+    let mut predicates: Vec<RowPredicate> = vec![];
+    for q in matches.opt_strs("query") {
+        match RowPredicate::parse(&q) {
+            Ok(p) => {
+                if !fields.unwrap_or_default().iter().any(|f| f == p.field()) {
+                    eprintln!("{}: {} is not a csv field", q, p.field());
+                    exit(1);
+                }
+                predicates.push(p);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+    }
+
+    let found = query_rows(rows, &predicates);
+    if found.is_empty() {
+        exit(1);
+    }
+
+    let sample = Ip {
+        address: found[0].start.clone(),
+        cidr: 0,
+    };
+    let formatted = if matches.opt_present("format") {
+        output_format(&sample, matches, None, config)
+    } else {
+        "%{_prefix}\n".to_string()
+    };
+
+    let merged = merge_ranges(
+        found
+            .iter()
+            .map(|m| (m.start.clone(), m.end.clone()))
+            .collect(),
+    );
+
+    if matches.opt_present("aggregate") {
+        // aggregated networks are not rows, so have no fields
+        let empty = NetRow {
+            row: HashMap::new(),
+        };
+        for (start, end) in &merged {
+            for net in range_networks(start, end) {
+                let m = RowMatch {
+                    start: net.address.clone(),
+                    end: broadcast(&net).address,
+                    network: Some(net),
+                    row: &empty,
+                };
+                out!("{}", format_row(&m, &formatted, None, config));
+            }
+        }
+    } else {
+        for m in &found {
+            out!("{}", format_row(m, &formatted, None, config));
+        }
+    }
+
+    if matches.opt_present("total") {
+        // overlapping rows are only counted once
+        let total: Count = merged.iter().map(|(s, e)| Count::span(s, e)).sum();
+        outln!("Total: {} rows, {} addresses", found.len(), total);
+    }
+
+    exit(0);
+}
+
+// csv addresses may also be plain integers, V4 unless too large for it or v6
+// is set
+fn parse_csv_address(s: &str, input_base: Option<i32>, reverse: bool, v6: bool) -> Option<Addr> {
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) {
+        return match s.parse::<u128>() {
+            Ok(n) if n <= u32::MAX as u128 && !v6 => Some(Addr::V4(Ipv4Addr::from(n as u32))),
+            Ok(n) => Some(Addr::V6(Ipv6Addr::from(n))),
+            Err(_) => None,
+        };
+    }
+
+    if s.contains(':') {
+        return parse_v6(s, input_base, reverse);
+    }
+
+    if s.contains('.') {
+        return parse_v4(s, input_base, reverse);
+    }
+
+    None
+}
+
+// a csv or network list for --diff, the fields are all but the network
+fn diff_entries(
+    path: &str,
+    field_name: &str,
+    matches: &getopts::Matches,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    config: &Mutex<Config>,
+) -> Vec<NetRange> {
+    if matches.opt_present("diff-lists") {
+        return network_list(path, input_base, reverse, config)
+            .iter()
+            .map(|ip| NetRange {
+                start: network(ip).address,
+                end: broadcast(ip).address,
+                row: NetRow {
+                    row: HashMap::new(),
+                },
+            })
+            .collect();
+    }
+
+    let mut reader = csv_reader(path, matches);
+    let (header_names, field_nums) = csv_columns(&mut reader, field_name);
+    let source = matches!(reverse, Reverse::Both | Reverse::Source);
+
+    let mut entries = vec![];
+    for result in reader.records() {
+        let record = match result {
+            Ok(r) => r,
+            Err(x) => {
+                eprintln!("{}: {}", path, x);
+                continue;
+            }
+        };
+        let (start, end) = match csv_key(&record, &field_nums, input_base, source) {
+            Ok(CsvKey::Network(ip)) => (network(&ip).address, broadcast(&ip).address),
+            Ok(CsvKey::Range(start, end)) => (start, end),
+            Err(None) => continue,
+            Err(Some(x)) => {
+                eprintln!("{}: {}", path, x);
+                continue;
+            }
+        };
+
+        let mut row = HashMap::new();
+        for (i, (name, value)) in header_names.iter().zip(record.iter()).enumerate() {
+            if !field_nums.contains(&i) {
+                row.insert(name.to_string(), value.to_string());
+            }
+        }
+        entries.push(NetRange {
+            start,
+            end,
+            row: NetRow { row },
+        });
+    }
+    entries
+}
+
+// network/cidr, or start-end when not on a network boundary
+fn range_text(start: &Addr, end: &Addr) -> String {
+    match range_network(start, end) {
+        Some(ip) => format!("{}/{}", ip, ip.cidr),
+        None => format!("{}-{}", start, end),
+    }
+}
+
+// the network field of the i-th --diff file
+fn diff_field(matches: &getopts::Matches, i: usize) -> String {
+    let fields = matches.opt_strs("field");
+    match fields.get(i).or(fields.first()) {
+        Some(f) => f.to_string(),
+        None if matches.opt_present("csv-noheader") => "1".to_string(),
+        None => "network".to_string(),
+    }
+}
+
+// the fields of the rows --diff compares
+fn diff_fields(matches: &getopts::Matches) -> Vec<String> {
+    let mut names = vec![];
+    if matches.opt_present("diff-lists") || matches.free.len() != 2 {
+        return names;
+    }
+    for (i, path) in matches.free.iter().enumerate() {
+        let (header_names, field_nums) =
+            csv_columns(&mut csv_reader(path, matches), &diff_field(matches, i));
+        for (j, name) in header_names.into_iter().enumerate() {
+            if !field_nums.contains(&j) && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+// compare two csv files or network lists, exit 1 when they differ
+fn diff(
+    matches: &getopts::Matches,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    config: &Mutex<Config>,
+) {
+    if matches.free.len() != 2 {
+        eprintln!("--diff needs an old and a new file");
+        exit(1);
+    }
+
+    let mut lists = vec![];
+    for (i, path) in matches.free.iter().enumerate() {
+        lists.push(diff_entries(
+            path,
+            &diff_field(matches, i),
+            matches,
+            input_base,
+            reverse,
+            config,
+        ));
+    }
+    let (old, new) = (&lists[0], &lists[1]);
+    let changes = diff_networks(old, new);
+
+    let text = |r: &NetRange| range_text(&r.start, &r.end);
+    let texts =
+        |v: &[usize], from: &[NetRange]| v.iter().map(|i| text(&from[*i])).collect::<Vec<String>>();
+    // fields that differ, with their old and new values
+    let changed_fields = |o: &NetRow, n: &NetRow| {
+        let mut names: Vec<&String> = o.row.keys().chain(n.row.keys()).collect();
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .filter(|f| o.row.get(*f) != n.row.get(*f))
+            .map(|f| {
+                let value = |r: &NetRow| r.row.get(f).cloned().unwrap_or_default();
+                (f.to_string(), value(o), value(n))
+            })
+            .collect::<Vec<(String, String, String)>>()
+    };
+    let row_text = |r: &NetRow| {
+        let mut v: Vec<String> = r.row.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        v.sort();
+        v.join(" ")
+    };
+
+    if matches.opt_present("diff-csv") {
+        let mut writer = csv::Writer::from_writer(Output);
+        let mut lines: Vec<[String; 6]> = vec![];
+        for change in &changes {
+            let e = String::new;
+            match change {
+                NetChange::Added(j) => {
+                    lines.push(["added".into(), e(), text(&new[*j]), e(), e(), e()])
+                }
+                NetChange::Removed(i) => {
+                    lines.push(["removed".into(), text(&old[*i]), e(), e(), e(), e()])
+                }
+                NetChange::Changed(i, j) => {
+                    for (f, o, n) in changed_fields(&old[*i].row, &new[*j].row) {
+                        lines.push(["changed".into(), text(&old[*i]), text(&new[*j]), f, o, n]);
+                    }
+                }
+                NetChange::Split(i, parts) => {
+                    for n in texts(parts, new) {
+                        lines.push(["split".into(), text(&old[*i]), n, e(), e(), e()]);
+                    }
+                }
+                NetChange::Merged(parts, j) => {
+                    for o in texts(parts, old) {
+                        lines.push(["merged".into(), o, text(&new[*j]), e(), e(), e()]);
+                    }
+                }
+            }
+        }
+
+        let mut result =
+            writer.write_record(["change", "old", "new", "field", "old_value", "new_value"]);
+        for line in lines {
+            if result.is_ok() {
+                result = writer.write_record(&line);
+            }
+        }
+        if let Err(x) = result.and(writer.flush().map_err(csv::Error::from)) {
+            eprintln!("{}", x);
+            exit(1);
+        }
+    } else {
+        for change in &changes {
+            match change {
+                NetChange::Added(j) => {
+                    let line = format!("+ {} {}", text(&new[*j]), row_text(&new[*j].row));
+                    outln!("{}", line.trim_end());
+                }
+                NetChange::Removed(i) => {
+                    let line = format!("- {} {}", text(&old[*i]), row_text(&old[*i].row));
+                    outln!("{}", line.trim_end());
+                }
+                NetChange::Changed(i, j) => {
+                    let fields: Vec<String> = changed_fields(&old[*i].row, &new[*j].row)
+                        .iter()
+                        .map(|(f, o, n)| format!("{}: {} -> {}", f, o, n))
+                        .collect();
+                    outln!("~ {} {}", text(&old[*i]), fields.join(", "));
+                }
+                NetChange::Split(i, parts) => {
+                    outln!(
+                        "< {} split into {}",
+                        text(&old[*i]),
+                        texts(parts, new).join(" ")
+                    );
+                }
+                NetChange::Merged(parts, j) => {
+                    outln!(
+                        "> {} merged from {}",
+                        text(&new[*j]),
+                        texts(parts, old).join(" ")
+                    );
+                }
+            }
+        }
+    }
+
+    exit(if changes.is_empty() { 0 } else { 1 });
+}
+
+// networks from a file, which may be followed by # comments
+fn network_list(
+    path: &str,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    config: &Mutex<Config>,
+) -> Vec<Ip> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Could not open {}: {}", path, e);
+            exit(1);
+        }
+    };
+    let text: Vec<&str> = text
+        .lines()
+        .map(|l| l.split('#').next().unwrap_or_default())
+        .collect();
+    let mut reader: Box<dyn BufRead> = Box::new(std::io::Cursor::new(text.join("\n")));
+    find_ips(&mut reader, input_base, reverse, config)
+        .flatten()
+        .collect()
+}
+
+fn input_reader(path: &str) -> Box<dyn BufRead> {
+    if path == "-" {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        let path = std::path::Path::new(&path);
+        if !path.exists() {
+            outln!(
+                "Could not open {} as it does not exist",
+                path.to_string_lossy()
+            );
+            exit(1);
+        }
+        Box::new(BufReader::new(File::open(path).unwrap()))
+    }
+}
+
+// split each network into named networks sized for their hosts
+fn vlsm_plan(matches: &getopts::Matches, ip_args: &[Ip], config: &Mutex<Config>) {
+    let mut needs: Vec<(String, u128)> = vec![];
+    for arg in matches.opt_strs("vlsm") {
+        for need in arg.split(|c: char| c.is_whitespace() || c == ',') {
+            if need.is_empty() {
+                continue;
+            }
+            match need.rsplit_once(':').map(|(n, h)| (n, h.parse::<u128>())) {
+                Some((name, Ok(hosts))) if !name.is_empty() => {
+                    needs.push((name.to_string(), hosts))
+                }
+                _ => {
+                    eprintln!("{}: not in name:hosts format", need);
+                    exit(1);
+                }
+            }
+        }
+    }
+
+    if ip_args.is_empty() {
+        eprintln!("--vlsm needs a network to divide");
+        exit(1);
+    }
+
+    let formatted = if matches.opt_present("format") {
+        output_format(&ip_args[0], matches, None, config)
+    } else {
+        "%{name} %a/%c\n".to_string()
+    };
+    let print = |name: &str, hosts: &str, ip: &Ip| {
+        let mut row = HashMap::new();
+        row.insert("name".to_string(), name.to_string());
+        row.insert("hosts".to_string(), hosts.to_string());
+        let m = RowMatch {
+            start: ip.address.clone(),
+            end: broadcast(ip).address,
+            network: Some(ip.clone()),
+            row: &NetRow { row },
+        };
+        out!("{}", format_row(&m, &formatted, None, config));
+    };
+
+    for arg in ip_args {
+        let net = network(arg);
+        let planned = match vlsm(&net, &needs) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        };
+
+        let mut used = vec![];
+        for (name, hosts, ip) in &planned {
+            print(name, &hosts.to_string(), ip);
+            used.push((ip.address.clone(), broadcast(ip).address));
+        }
+
+        // what is left over is named free
+        for (start, end) in free_ranges(&net, used) {
+            for ip in range_networks(&start, &end) {
+                print("free", "", &ip);
+            }
+        }
+    }
+
+    exit(0);
+}
+
+// a unique local /48 from the time and a mac, or from --ula-seed, the csv is
+// only checked, it would not match the new prefix
+fn ula(matches: &getopts::Matches, rows: &Option<NetRows>, config: &Mutex<Config>) {
+    let seed = matches.opt_str("ula-seed");
+    let check = matches.opt_present("ula-check");
+    let local = if check { local_v6_addresses() } else { vec![] };
+    let eui64 = local_eui64().unwrap_or_else(|| std::process::id() as u64);
+
+    // a new time gives a new prefix when one is taken
+    for _ in 0..16 {
+        let prefix = match &seed {
+            Some(seed) => ula_prefix(seed.as_bytes()),
+            None => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default();
+                ula_prefix(&ula_key(now, eui64))
+            }
+        };
+
+        if !check || !net_in_use(&prefix, rows, &local) {
+            print_details(&prefix, matches, &None, None, config);
+            exit(0);
+        }
+        if seed.is_some() {
+            eprintln!("{}/{} is already used", prefix, prefix.cidr);
+            exit(1);
+        }
+    }
+
+    eprintln!("could not find an unused prefix");
+    exit(1);
+}
+
+// a stable host for each name in each network, missing those in -s
+fn assign_hosts(
+    matches: &getopts::Matches,
+    ip_args: &[Ip],
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    config: &Mutex<Config>,
+) {
+    if ip_args.is_empty() {
+        eprintln!("--assign needs a network");
+        exit(1);
+    }
+
+    let mut used: Vec<(Addr, Addr)> = vec![];
+    let path = match matches.opt_str("file") {
+        Some(p) => Some(p),
+        None if fd_ready(std::io::stdin().as_raw_fd()) => Some("-".to_string()),
+        None => None,
+    };
+    if let Some(path) = path {
+        let mut reader = input_reader(&path);
+        for a in find_ips(&mut reader, input_base, reverse, config) {
+            for ip in a {
+                used.push((network(&ip).address, broadcast(&ip).address));
+            }
+        }
+    }
+
+    let formatted = if matches.opt_present("format") {
+        output_format(&ip_args[0], matches, None, config)
+    } else {
+        "%{name} %a\n".to_string()
+    };
+
+    for arg in ip_args {
+        for name in matches.opt_strs("assign") {
+            let ip = match assign(&name, arg, &used) {
+                Some(ip) => ip,
+                None => {
+                    eprintln!("no free address for {} in {}/{}", name, arg, arg.cidr);
+                    exit(1);
+                }
+            };
+            // later names do not take the same address
+            used.push((ip.address.clone(), ip.address.clone()));
+
+            let mut row = HashMap::new();
+            row.insert("name".to_string(), name.to_string());
+            let m = RowMatch {
+                start: ip.address.clone(),
+                end: ip.address.clone(),
+                network: Some(ip.clone()),
+                row: &NetRow { row },
+            };
+            out!("{}", format_row(&m, &formatted, None, config));
+        }
+    }
+
+    exit(0);
+}
+
+// --offset, --limit, --step and --descending for --list and --divide
+fn walk_opts(matches: &getopts::Matches) -> Walk {
+    let step = count_opt(matches, "step").unwrap_or(1);
+    if step == 0 {
+        eprintln!("--step must be at least 1");
+        exit(1);
+    }
+    Walk {
+        offset: count_opt(matches, "offset").unwrap_or(0),
+        limit: count_opt(matches, "limit"),
+        step,
+        descending: matches.opt_present("descending"),
+    }
+}
+
+// a count option, which may be 2^N
+fn count_opt(matches: &getopts::Matches, name: &str) -> Option<u128> {
+    let value = matches.opt_str(name)?;
+    match parse_count(&value) {
+        Some(n) => Some(n),
+        None => {
+            eprintln!("--{} {}: not a number", name, value);
+            exit(1);
+        }
+    }
+}
+
+// the networks of a plan, each level number a field of the row
+fn plan_output(
+    matches: &getopts::Matches,
+    ip_args: &[Ip],
+    levels: &[PlanLevel],
+    config: &Mutex<Config>,
+) {
+    if ip_args.is_empty() {
+        eprintln!("--plan needs a network to divide");
+        exit(1);
+    }
+
+    let formatted = if matches.opt_present("format") {
+        output_format(&ip_args[0], matches, None, config)
+    } else {
+        let mut f = "%a/%c".to_string();
+        for level in levels {
+            f.push_str(&format!(" {} %{{{}}}", level.name, level.name));
+        }
+        f.push('\n');
+        f
+    };
+
+    let offset = count_opt(matches, "offset").unwrap_or(0);
+    let limit = count_opt(matches, "limit");
+
+    for arg in ip_args {
+        let plan = match Plan::new(arg, levels.to_vec()) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        };
+
+        let end = limit.map_or(plan.len, |l| offset.saturating_add(l));
+        for (numbers, ip) in plan.range(offset, end) {
+            let mut row = HashMap::new();
+            for (level, n) in levels.iter().zip(numbers) {
+                row.insert(level.name.to_string(), level.number(n));
+            }
+            let m = RowMatch {
+                start: ip.address.clone(),
+                end: broadcast(&ip).address,
+                network: Some(ip.clone()),
+                row: &NetRow { row },
+            };
+            out!("{}", format_row(&m, &formatted, None, config));
+        }
+    }
+
+    exit(0);
+}
+
+// list the blocks of each network not used by -s or --csv networks
+fn free_blocks(
+    matches: &getopts::Matches,
+    ip_args: &[Ip],
+    rows: &Option<NetRows>,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    config: &Mutex<Config>,
+) {
+    let mut used: Vec<(Addr, Addr)> = vec![];
+
+    let path = match matches.opt_str("file") {
+        Some(p) => Some(p),
+        None if fd_ready(std::io::stdin().as_raw_fd()) => Some("-".to_string()),
+        None => None,
+    };
+    if let Some(path) = path {
+        let mut reader = input_reader(&path);
+        for a in find_ips(&mut reader, input_base, reverse, config) {
+            for ip in a {
+                used.push((network(&ip).address, broadcast(&ip).address));
+            }
+        }
+    }
+
+    if let Some(rows) = rows {
+        for m in rows.all_rows() {
+            used.push((m.start, m.end));
+        }
+    }
+
+    let min_size = match matches.opt_str("free-size") {
+        Some(s) => match parse_mask(&s) {
+            Some(m) => Some(m),
+            None => {
+                eprintln!("Invalid --free-size {}", s);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let formatted = if matches.opt_present("format") {
+        None
+    } else {
+        Some("%a/%c\n".to_string())
+    };
+
+    let mut blocks = 0;
+    let mut total = Count::default();
+    for arg in ip_args {
+        for (start, end) in free_ranges(arg, used.clone()) {
+            for block in range_networks(&start, &end) {
+                if min_size.is_some_and(|m| block.cidr > m) {
+                    continue;
+                }
+
+                let f = formatted
+                    .clone()
+                    .unwrap_or_else(|| output_format(&block, matches, None, config));
+                if let Some(m) = format_details(&block, f, &None, None, Some(matches), config) {
+                    out!("{}", m);
+                }
+                blocks += 1;
+                total += network_size(&block);
+            }
+        }
+    }
+
+    if matches.opt_present("total") {
+        outln!("Total: {} blocks, {} addresses", blocks, total);
+    }
+
+    exit(if blocks > 0 { 0 } else { 1 });
+}
+
+// hits, distinct addresses and utilisation of each --csv row from -s
+fn report(
+    matches: &getopts::Matches,
+    rows: &Option<NetRows>,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    config: &Mutex<Config>,
+) {
+    let rows = match rows {
+        Some(r) => r,
+        None => {
+            eprintln!("--report needs a --csv");
+            exit(1);
+        }
+    };
+    let path = match matches.opt_str("file") {
+        Some(p) => p,
+        None => "-".to_string(),
+    };
+
+    let all = query_rows(rows, &[]);
+    let mut index: HashMap<(Addr, Addr), usize> = HashMap::new();
+    for (i, m) in all.iter().enumerate() {
+        index.insert((m.start.clone(), m.end.clone()), i);
+    }
+    let mut usage: Vec<RowUsage> = all.iter().map(|_| RowUsage::default()).collect();
+
+    let mut unmatched: Vec<Ip> = vec![];
+    let mut unmatched_seen: HashMap<Ip, bool> = HashMap::new();
+
+    let mut reader = input_reader(&path);
+    for a in find_ips(&mut reader, input_base, reverse, config) {
+        for ip in a {
+            // every row holding all of ip, so parents count their children
+            let b = broadcast(&ip).address;
+            let mut found = false;
+            for m in matching_rows(&network(&ip), rows) {
+                if m.end < b {
+                    continue;
+                }
+                if let Some(i) = index.get(&(m.start, m.end)) {
+                    usage[*i].add(&ip);
+                    found = true;
+                }
+            }
+
+            if !found && unmatched_seen.insert(ip.clone(), true).is_none() {
+                unmatched.push(ip);
+            }
+        }
+    }
+
+    outln!("network\thits\tdistinct\tused\tfirst\tlast");
+    for (m, u) in all.iter().zip(usage.iter()) {
+        let size = Count::span(&m.start, &m.end).as_f64();
+        let distinct = u.distinct();
+        let seen = |ip: &Option<Ip>| match ip {
+            Some(ip) if ip.cidr == ip.address.width() => ip.to_string(),
+            Some(ip) => format!("{}/{}", ip, ip.cidr),
+            None => "-".to_string(),
+        };
+        outln!(
+            "{}\t{}\t{}\t{:.2}%\t{}\t{}",
+            m.prefix(),
+            u.hits,
+            distinct,
+            distinct.as_f64() * 100.0 / size,
+            seen(&u.first),
+            seen(&u.last)
+        );
+    }
+
+    let unused: Vec<&RowMatch> = all
+        .iter()
+        .zip(usage.iter())
+        .filter(|(_, u)| u.hits == 0)
+        .map(|(m, _)| m)
+        .collect();
+    if !unused.is_empty() {
+        outln!();
+        outln!("no hits");
+        for m in unused {
+            outln!("{}", m.prefix());
+        }
+    }
+
+    if !unmatched.is_empty() {
+        outln!();
+        outln!("unmatched");
+        for ip in unmatched {
+            if ip.cidr == ip.address.width() {
+                outln!("{}", ip);
+            } else {
+                outln!("{}/{}", ip, ip.cidr);
+            }
+        }
+    }
+
+    exit(0);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_input_file(
+    path: &str,
+    matches: &getopts::Matches,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    ip_args: &[Ip],
+    rows: &Option<NetRows>,
+    reference: &Option<NetRows>,
+    inside: Option<bool>,
+    config: &Mutex<Config>,
+) {
+    let mut reader = input_reader(path);
+
     if matches.opt_present("available") {
-        let mut used: HashMap<Addr, bool> = HashMap::new();
+        // used networks take their whole range
+        let mut used: Vec<(Addr, Addr)> = vec![];
         for a in find_ips(&mut reader, input_base, reverse, config) {
             for ip in a {
-                used.insert(ip.address, true);
+                used.push((network(&ip).address, broadcast(&ip).address));
+            }
+            // keep memory down for long inputs
+            if used.len() >= 65536 && used.len().is_power_of_two() {
+                used = merge_ranges(used);
+            }
+        }
+        let used = merge_ranges(used);
+
+        for arg in ip_args {
+            match matches.opt_str("available-as").as_deref() {
+                None | Some("addresses") => print_details(arg, matches, rows, Some(&used), config),
+                Some("ranges") => {
+                    for (start, end) in free_ranges(arg, used.clone()) {
+                        if start == end {
+                            outln!("{}", start);
+                        } else {
+                            outln!("{}-{}", start, end);
+                        }
+                    }
+                }
+                Some("networks") => {
+                    let formatted = if matches.opt_present("format") {
+                        None
+                    } else {
+                        Some("%a/%c\n".to_string())
+                    };
+                    for (start, end) in free_ranges(arg, used.clone()) {
+                        for block in range_networks(&start, &end) {
+                            let f = formatted
+                                .clone()
+                                .unwrap_or_else(|| output_format(&block, matches, None, config));
+                            if let Some(m) =
+                                format_details(&block, f, rows, None, Some(matches), config)
+                            {
+                                out!("{}", m);
+                            }
+                        }
+                    }
+                }
+                Some(other) => {
+                    eprintln!(
+                        "--available-as {}: not addresses, ranges or networks",
+                        other
+                    );
+                    exit(1);
+                }
             }
         }
-
-        for arg in ip_args {
-            print_details(arg, matches, rows, Some(&used), config);
-        }
-        std::process::exit(0);
+        exit(0);
     }
 
     if matches.opt_present("encapsulating") {
@@ -288,7 +2190,7 @@ fn process_input_file(
                 }
                 None => {
                     eprintln!("Could not find an encapsulating network, sorry");
-                    std::process::exit(1);
+                    exit(1);
                 }
             }
         } else {
@@ -298,58 +2200,180 @@ fn process_input_file(
                 }
                 None => {
                     eprintln!("Could not find an encapsulating network, sorry");
-                    std::process::exit(1);
+                    exit(1);
                 }
             }
         }
 
-        std::process::exit(0);
+        exit(0);
     }
 
     let mut found_match = false;
 
-    for a in find_ips(&mut reader, input_base, reverse, config) {
-        for ip in a {
-            match inside {
-                Some(true) => {
-                    let mut found = false;
-                    for arg in ip_args {
-                        if within(arg, &ip) {
-                            found = true;
-                            break;
-                        }
-                    }
+    // with --csv-filter, fields come from the rows that were filtered
+    let inside_rows = if reference.is_some() && matches.opt_present("csv-filter") {
+        reference
+    } else {
+        rows
+    };
 
-                    if found {
-                        found_match = true;
-                        print_details(&ip, matches, rows, None, config);
-                    }
-                }
-                Some(false) => {
-                    let mut found = false;
+    let threads = match matches.opt_str("threads").map(|x| x.parse::<usize>()) {
+        None => None,
+        Some(Ok(x)) if x > 0 => Some(x),
+        Some(_) => {
+            eprintln!("--threads must be a number of at least 1");
+            exit(1);
+        }
+    };
 
-                    for arg in ip_args {
-                        if within(arg, &ip) {
-                            found = true;
-                            break;
-                        }
-                    }
+    let filter = InputFilter {
+        matches,
+        ip_args,
+        rows,
+        inside_rows,
+        reference,
+        inside,
+        config,
+    };
 
-                    if !found {
+    match threads {
+        Some(n) => {
+            found_match = threaded_input(&mut reader, n, input_base, reverse, &filter);
+        }
+        None => {
+            for a in find_ips(&mut reader, input_base, reverse, config) {
+                for ip in a {
+                    if filter.emit(&ip, &mut |m| out!("{}", m)) {
                         found_match = true;
-                        print_details(&ip, matches, rows, None, config);
                     }
                 }
-                None => {
-                    print_details(&ip, matches, rows, None, config);
-                }
             }
         }
     }
 
     if !found_match && inside.is_some() {
-        std::process::exit(1);
+        exit(1);
+    }
+}
+
+// how -s input is checked against --inside or --outside before display
+struct InputFilter<'a> {
+    matches: &'a getopts::Matches,
+    ip_args: &'a [Ip],
+    rows: &'a Option<NetRows>,
+    inside_rows: &'a Option<NetRows>,
+    reference: &'a Option<NetRows>,
+    inside: Option<bool>,
+    config: &'a Mutex<Config>,
+}
+
+impl InputFilter<'_> {
+    // emit the details of ip if it passes, true when it did
+    fn emit(&self, ip: &Ip, emit: &mut dyn FnMut(&str)) -> bool {
+        let inside = match self.inside {
+            Some(x) => x,
+            None => {
+                emit_details(ip, self.matches, self.rows, None, self.config, emit);
+                return false;
+            }
+        };
+
+        let mut found = self.ip_args.iter().any(|arg| within(arg, ip));
+
+        if let Some(r) = self.reference {
+            if row_within(r, ip).is_some() {
+                found = true;
+            }
+        }
+
+        if found != inside {
+            return false;
+        }
+
+        let rows = if inside { self.inside_rows } else { self.rows };
+        emit_details(ip, self.matches, rows, None, self.config, emit);
+        true
+    }
+}
+
+const THREAD_BATCH: usize = 4096;
+
+// lines are read in batches and each batch is parsed and formatted on the
+// pool, output keeps input order unless --unordered
+fn threaded_input(
+    reader: &mut Box<dyn BufRead>,
+    threads: usize,
+    input_base: Option<i32>,
+    reverse: &Reverse,
+    filter: &InputFilter,
+) -> bool {
+    let pool = match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("cannot start {} threads: {}", threads, e);
+            exit(1);
+        }
+    };
+
+    let unordered = filter.matches.opt_present("unordered");
+    let found_match = AtomicBool::new(false);
+
+    // each result, %i is numbered as they are written
+    let render = |line: &String| -> Vec<String> {
+        let mut results = vec![];
+        for ip in line_ips(line, input_base, reverse, filter.config) {
+            if filter.emit(&ip, &mut |m| results.push(m.to_string())) {
+                found_match.store(true, Ordering::Relaxed);
+            }
+        }
+        results
+    };
+    filter.config.lock().unwrap().defer_index = true;
+    let index = Mutex::new(filter.config.lock().unwrap().output_index);
+    let write = |results: Vec<String>| {
+        let mut index = index.lock().unwrap();
+        let mut out = String::new();
+        // one emit holds several results with --all-matches
+        for r in results {
+            let mut parts = r.split(RESULT_MARK).peekable();
+            while let Some(part) = parts.next() {
+                if parts.peek().is_none() {
+                    out.push_str(part);
+                    break;
+                }
+                out.push_str(&part.replace(INDEX_MARK, &index.to_string()));
+                *index += 1;
+            }
+        }
+        out!("{}", out);
+    };
+
+    // waiting for a full batch would hold back lines from tail -f
+    let batch_size = if LINE_BUFFERED.load(Ordering::Relaxed) {
+        1
+    } else {
+        THREAD_BATCH
+    };
+
+    loop {
+        let batch: Vec<String> = std::iter::from_fn(|| input_line(reader))
+            .take(batch_size)
+            .collect();
+        if batch.is_empty() {
+            break;
+        }
+
+        if unordered {
+            pool.install(|| batch.par_iter().for_each(|line| write(render(line))));
+        } else {
+            let done: Vec<Vec<String>> = pool.install(|| batch.par_iter().map(render).collect());
+            for results in done {
+                write(results);
+            }
+        }
     }
+
+    found_match.load(Ordering::Relaxed)
 }
 
 fn wait_stdin(matches: &getopts::Matches) -> bool {
@@ -359,6 +2383,10 @@ fn wait_stdin(matches: &getopts::Matches) -> bool {
     if matches.opt_present("list")
         || matches.opt_present("inside")
         || matches.opt_present("outside")
+        || matches.opt_present("inside-file")
+        || matches.opt_present("outside-file")
+        || matches.opt_present("inside-csv")
+        || matches.opt_present("outside-csv")
     {
         return true;
     }
@@ -370,32 +2398,208 @@ fn wait_stdin(matches: &getopts::Matches) -> bool {
 
 fn main() {
     let mut opts = Options::new();
-    let mut rows: Option<HashMap<Ip, NetRow>> = None;
+    let mut rows: Option<NetRows> = None;
     let mut input_ip: Option<Addr> = None;
     let mut input_mask: Option<u32> = None;
     let mut input_base: Option<i32> = None;
     let mut reverse = Reverse::None;
     let mut inside: Option<bool> = None;
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
     let mut ip_args: Vec<Ip> = vec![];
-    let config = RefCell::new(Config {
-        interface_names: vec![],
-        hm: HashMap::new(),
-    });
+    let mut csv_fields: Option<Vec<String>> = None;
+    let config = Mutex::new(Config::default());
 
     opts.parsing_style(getopts::ParsingStyle::FloatingFrees);
     opts.optopt("4", "ipv4", "ipv4 address", "IPv4");
     opts.optopt("6", "ipv6", "ipv6 address", "IPv6");
 
     opts.optflag("a", "available", "display unused addresses");
+    opts.optopt(
+        "",
+        "available-as",
+        "--available as addresses, ranges or networks",
+        "FORM",
+    );
+    opts.optflag(
+        "",
+        "free",
+        "display networks not used by -s or --csv as the fewest blocks",
+    );
+    opts.optopt(
+        "",
+        "free-size",
+        "only display --free blocks of at least this size",
+        "CIDR",
+    );
+    opts.optopt(
+        "",
+        "allocate",
+        "add a free network of this size in the pool to the --csv ledger",
+        "CIDR",
+    );
+    opts.optflag("", "release", "remove networks from the --csv ledger");
+    opts.optopt("", "fit", "first or best free space for --allocate", "FIT");
+    opts.optopt(
+        "",
+        "align",
+        "--allocate only on boundaries of this size",
+        "CIDR",
+    );
+    opts.optmulti(
+        "",
+        "set",
+        "field value for the --allocate ledger row",
+        "FIELD=VALUE",
+    );
     opts.optflag(
         "",
         "allowemptyrow",
         "when no matching csv network, use empty fields",
     );
+    opts.optmulti(
+        "",
+        "vlsm",
+        "divide network into named networks for host counts",
+        "NAME:HOSTS",
+    );
+    opts.optmulti(
+        "",
+        "plan",
+        "divide network into levels of named bits, only the numbers given",
+        "NAME:BITS[=N,N-N]",
+    );
+    opts.optopt(
+        "",
+        "offset",
+        "start --list, --divide or --plan at this position",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "limit",
+        "display at most this many --list, --divide or --plan results",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "step",
+        "move this many addresses or networks at a time in --list or --divide",
+        "N",
+    );
+    opts.optflag("", "descending", "--list or --divide from the end");
+    opts.optmulti(
+        "",
+        "assign",
+        "a stable address for the name in the network, avoiding -s",
+        "NAME",
+    );
+    opts.optflag("", "ula", "generate a RFC 4193 unique local /48");
+    opts.optopt(
+        "",
+        "ula-seed",
+        "generate the --ula from this name rather than the time",
+        "NAME",
+    );
+    opts.optflag(
+        "",
+        "ula-check",
+        "generate a --ula not used in --csv or on local interfaces",
+    );
+    opts.optflag(
+        "",
+        "diff",
+        "compare an old and a new csv, exit 1 when they differ",
+    );
+    opts.optflag(
+        "",
+        "diff-lists",
+        "--diff network lists rather than csv files",
+    );
+    opts.optflag("", "diff-csv", "--diff output as csv");
+    opts.optflag(
+        "",
+        "report",
+        "hits and utilisation of each --csv row by the addresses in -s",
+    );
+    opts.optflag(
+        "",
+        "all-matches",
+        "output once for every matching csv row, most specific first",
+    );
+    opts.optmulti(
+        "",
+        "query",
+        "list csv rows where field=value, field!=value or field~regex",
+        "PREDICATE",
+    );
+    opts.optflag("", "aggregate", "join --query results into fewest networks");
+    opts.optflag(
+        "",
+        "total",
+        "print the number of --query or --free addresses",
+    );
     opts.optopt("b", "base", "ipv4 base format, default to oct", "INTEGER");
-    opts.optopt("c", "csv", "csv reference file", "PATH");
+    opts.optmulti(
+        "c",
+        "csv",
+        "csv reference file, repeat to layer files, later files take precedence",
+        "[ALIAS=]PATH",
+    );
+    opts.optopt(
+        "",
+        "csv-delimiter",
+        "csv field delimiter, default ,",
+        "CHAR",
+    );
+    opts.optopt("", "csv-quote", "csv quote character, default \"", "CHAR");
+    opts.optopt(
+        "",
+        "csv-comment",
+        "ignore csv lines starting with this",
+        "CHAR",
+    );
+    opts.optflag(
+        "",
+        "csv-noheader",
+        "csv has no header row, fields are numbered from 1",
+    );
+    opts.optflag("", "csv-trim", "trim space around csv fields");
+    opts.optopt(
+        "",
+        "csv-encoding",
+        "csv encoding, utf-8, latin1, windows-1252, utf-16le or utf-16be",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "index",
+        "read --csv rows from this compiled index while the csv is unchanged",
+        "PATH",
+    );
+    opts.optflag("", "compile-index", "compile the --csv files to --index");
+    opts.optflag(
+        "",
+        "check-csv",
+        "report non-canonical, duplicate, overlapping and unreadable --csv rows",
+    );
+    opts.optflag(
+        "",
+        "canonicalise-csv",
+        "rewrite --csv files in numeric order with canonical networks",
+    );
+    opts.optopt(
+        "",
+        "config",
+        "config file, default $XDG_CONFIG_HOME/ripcalc/config.toml",
+        "PATH",
+    );
     opts.optopt("d", "divide", "divide network into chunks", "CIDR");
+    opts.optopt(
+        "",
+        "host-index",
+        "the address at this index of each network, negative from the end",
+        "N",
+    );
     opts.optflag("", "noexpand", "do not expand networks in list");
 
     opts.optflag(
@@ -405,6 +2609,11 @@ fn main() {
     );
 
     opts.optopt("f", "format", "format output\n'cidr' expands to %a/%c\\n\n'short' expands to %a\\n\nSee manual for more options", "STRING");
+    opts.optflag(
+        "",
+        "explain-format",
+        "describe each token of the format with a sample and exit",
+    );
     opts.optopt(
         "",
         "group",
@@ -413,7 +2622,12 @@ fn main() {
     );
     opts.optflag("h", "help", "display help");
 
-    opts.optopt("i", "field", "csv field", "FIELD");
+    opts.optmulti(
+        "i",
+        "field",
+        "csv network field or start,end fields, once per --csv",
+        "FIELD",
+    );
     opts.optflag("l", "list", "list all addresses in network");
     opts.optflag(
         "",
@@ -421,6 +2635,26 @@ fn main() {
         "display when extremities are outside network",
     );
     opts.optflag("", "inside", "display when extremities are inside network");
+    opts.optopt(
+        "",
+        "inside-file",
+        "as --inside, with networks listed in a file",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "outside-file",
+        "as --outside, with networks listed in a file",
+        "PATH",
+    );
+    opts.optflag("", "inside-csv", "as --inside, with the --csv networks");
+    opts.optflag("", "outside-csv", "as --outside, with the --csv networks");
+    opts.optmulti(
+        "",
+        "csv-filter",
+        "only use --csv rows where field=value, field!=value or field~regex for --inside-csv/--outside-csv",
+        "PREDICATE",
+    );
     opts.optopt("m", "mask", "cidr mask", "CIDR");
     opts.optopt(
         "n",
@@ -436,36 +2670,67 @@ fn main() {
         "",
     );
     opts.optopt("s", "file", "lookup addresses from, - for stdin", "PATH");
+    opts.optopt("", "threads", "process -s input on this many threads", "N");
+    opts.optflag("", "unordered", "--threads output in the order finished");
+    opts.optflag(
+        "",
+        "line-buffered",
+        "write output a line at a time, for tail -f and similar",
+    );
 
     opts.optflag("v", "version", "print version");
 
-    let matches = match opts.parse(&args[1..]) {
+    let mut matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
-            println!("{}", f);
-            std::process::exit(1);
+            outln!("{}", f);
+            exit(1);
         }
     };
 
     if matches.opt_present("h") {
-        println!("{}", opts.usage(&banner()));
+        outln!("{}", opts.usage(&banner()));
 
-        std::process::exit(0);
+        exit(0);
     }
 
     if matches.opt_present("version") {
         print_version();
-        std::process::exit(0);
+        exit(0);
+    }
+
+    if matches.opt_present("line-buffered") || std::io::stdout().is_terminal() {
+        LINE_BUFFERED.store(true, Ordering::Relaxed);
+    }
+
+    let file_config = read_config(&matches);
+    let extra_args = config_args(&matches, &file_config);
+    if !extra_args.is_empty() {
+        args.splice(1..1, extra_args);
+        matches = match opts.parse(&args[1..]) {
+            Ok(m) => m,
+            Err(f) => {
+                outln!("{}", f);
+                exit(1);
+            }
+        };
     }
+    config.lock().unwrap().formats = file_config.formats.clone();
 
-    if matches.opt_present("inside") {
+    if matches.opt_present("inside")
+        || matches.opt_present("inside-file")
+        || matches.opt_present("inside-csv")
+    {
         inside = Some(true);
     }
 
-    if matches.opt_present("outside") {
+    if matches.opt_present("outside")
+        || matches.opt_present("outside-file")
+        || matches.opt_present("outside-csv")
+    {
         if inside.is_some() {
-            println!("Cannot combine --inside and --outside");
-            std::process::exit(1);
+            outln!("Cannot combine --inside and --outside");
+            exit(1);
         }
         inside = Some(false);
     }
@@ -475,7 +2740,7 @@ fn main() {
             Ok(x) => x,
             Err(x) => {
                 eprintln!("Cannot convert {} to number", x);
-                std::process::exit(1);
+                exit(1);
             }
         };
     }
@@ -485,13 +2750,16 @@ fn main() {
             Ok(x) => x,
             Err(x) => {
                 eprintln!("Cannot convert {} to number", x);
-                std::process::exit(1);
+                exit(1);
             }
         };
     }
 
     if matches.opt_present("reverse") {
         match matches.opt_str("reverse").unwrap().as_str() {
+            "none" => {
+                reverse = Reverse::None;
+            }
             "inputs" => {
                 reverse = Reverse::Input;
             }
@@ -502,8 +2770,8 @@ fn main() {
                 reverse = Reverse::Both;
             }
             _ => {
-                println!("reverse is not one of inputs, sources or both");
-                std::process::exit(1);
+                outln!("reverse is not one of none, inputs, sources or both");
+                exit(1);
             }
         }
     }
@@ -512,28 +2780,123 @@ fn main() {
         input_base = match i32::from_str(&matches.opt_str("b").unwrap()) {
             Ok(x) => Some(x),
             Err(x) => {
-                println!("Cannot convert to an integer base: {}", x);
-                std::process::exit(1);
+                outln!("Cannot convert to an integer base: {}", x);
+                exit(1);
             }
         };
     }
 
-    if matches.opt_present("csv") {
-        let path = matches.opt_str("csv").unwrap();
-        let reader = match csv::Reader::from_path(&path) {
-            Ok(r) => r,
-            Err(x) => {
-                eprintln!("Cannot open {}: {}", &path, x);
-                std::process::exit(1);
+    // read once --base and --reverse are known
+    for path in vec![
+        matches.opt_str("inside-file"),
+        matches.opt_str("outside-file"),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        ip_args.extend(network_list(&path, input_base, &reverse, &config));
+    }
+
+    // a fresh --index stands in for reading the csv files
+    let index_key = index_key(&matches);
+    let mut index = match matches.opt_str("index") {
+        Some(path) if !matches.opt_present("compile-index") => {
+            if !matches.opt_present("csv") {
+                eprintln!("--index needs the --csv it was compiled from");
+                exit(1);
             }
+            fresh_index(&path, &index_key)
+        }
+        _ => None,
+    };
+    let mut index_sources: Vec<IndexSource> = vec![];
+
+    let field_names = matches.opt_strs("field");
+    let mut csv_problems = 0;
+    for (i, arg) in matches.opt_strs("csv").iter().enumerate() {
+        let (alias, path) = csv_alias(arg);
+        let field_name = match field_names.get(i) {
+            Some(f) => f.to_string(),
+            None if matches.opt_present("csv-noheader") => "1".to_string(),
+            None => "network".to_string(),
         };
-        let field_name = if matches.opt_present("field") {
-            matches.opt_str("field").unwrap()
-        } else {
-            "network".to_string()
+
+        // the ledger is read while locked
+        if matches.opt_present("allocate") || matches.opt_present("release") {
+            continue;
+        }
+
+        if matches.opt_present("check-csv") {
+            csv_problems += check_csv(&path, &field_name, &matches, input_base, &reverse);
+            continue;
+        }
+
+        if matches.opt_present("canonicalise-csv") {
+            canonicalise_csv(&path, &field_name, &matches, input_base, &reverse);
+            continue;
+        }
+
+        if index.is_some() {
+            continue;
+        }
+
+        if matches.opt_present("compile-index") {
+            match IndexSource::read(&path, true) {
+                Ok(s) => index_sources.push(s),
+                Err(x) => {
+                    eprintln!("Cannot open {}", x);
+                    exit(1);
+                }
+            }
+        }
+
+        let reader = csv_reader(&path, &matches);
+        let fields = process_csv(reader, &alias, field_name, &mut rows, input_base, &reverse);
+        csv_fields.get_or_insert_with(Vec::new).extend(fields);
+    }
+    if matches.opt_present("check-csv") {
+        exit(if csv_problems > 0 { 1 } else { 0 });
+    }
+    if matches.opt_present("canonicalise-csv") {
+        exit(0);
+    }
+
+    if let Some(rows) = rows.as_mut() {
+        rows.sort_ranges();
+    }
+
+    if matches.opt_present("compile-index") {
+        write_index(&matches, &rows, &csv_fields, &index_sources, &index_key);
+        exit(0);
+    }
+
+    if let Some(index) = index.take() {
+        csv_fields = Some(index.fields.clone());
+        rows = Some(NetRows::from(index));
+    }
+
+    // the csv rows, or some of them, as the inside/outside networks
+    let mut reference: Option<NetRows> = None;
+    if matches.opt_present("inside-csv") || matches.opt_present("outside-csv") {
+        let rows = match &rows {
+            Some(r) => r,
+            None => {
+                eprintln!("--inside-csv and --outside-csv need a --csv");
+                exit(1);
+            }
         };
 
-        process_csv(reader, field_name, &mut rows, input_base, &reverse);
+        let mut predicates: Vec<RowPredicate> = vec![];
+        for f in matches.opt_strs("csv-filter") {
+            match RowPredicate::parse(&f) {
+                Ok(p) => predicates.push(p),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        reference = Some(filter_rows(rows, &predicates));
     }
 
     if let Some(v) = matches.opt_str("mask") {
@@ -556,28 +2919,84 @@ fn main() {
         );
     }
 
-    if input_mask.is_none() && matches.free.is_empty() {
-        input_mask = Some(24);
-
-        if let Some(Addr::V6(_)) = input_ip {
-            input_mask = Some(64);
-        }
-    }
+    let v4_mask = input_mask.or(file_config.ipv4_mask);
+    let v6_mask = input_mask.or(file_config.ipv6_mask);
 
     if let Some(input_ip) = input_ip {
         ip_args.push(Ip {
+            cidr: match input_ip {
+                Addr::V4(_) => v4_mask.unwrap_or(24),
+                Addr::V6(_) => v6_mask.unwrap_or(64),
+            },
             address: input_ip,
-            cidr: input_mask.unwrap(),
         });
     }
 
+    // planned networks have their name and host count as fields
+    if matches.opt_present("vlsm") {
+        csv_fields
+            .get_or_insert_with(Vec::new)
+            .extend(["name".to_string(), "hosts".to_string()]);
+    }
+    if matches.opt_present("assign") {
+        csv_fields
+            .get_or_insert_with(Vec::new)
+            .push("name".to_string());
+    }
+
+    // plan levels are fields named after them
+    let mut plan_levels = vec![];
+    for spec in matches.opt_strs("plan") {
+        match parse_plan(&spec) {
+            Ok(levels) => plan_levels.extend(levels),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+    }
+    if !plan_levels.is_empty() {
+        csv_fields
+            .get_or_insert_with(Vec::new)
+            .extend(plan_levels.iter().map(|l| l.name.to_string()));
+    }
+
+    // the format is checked before a ledger or diff is read or written, with
+    // the fields of the rows it is given
+    if matches.opt_present("format") && !matches.opt_present("explain-format") {
+        let fields = if matches.opt_present("allocate") || matches.opt_present("release") {
+            let (path, field_name) = ledger_path(&matches);
+            Some(ledger_fields(&path, &field_name, &matches))
+        } else if matches.opt_present("diff") {
+            Some(diff_fields(&matches))
+        } else {
+            csv_fields.clone()
+        };
+        let sample = Ip {
+            address: Addr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1)),
+            cidr: 24,
+        };
+        if let Err(e) = lint_format(
+            &output_format(&sample, &matches, None, &config),
+            fields.as_deref(),
+            &config.lock().unwrap(),
+        ) {
+            eprintln!("Invalid format {}", e);
+            exit(1);
+        }
+    }
+
+    if matches.opt_present("diff") {
+        diff(&matches, input_base, &reverse, &config);
+    }
+
     let free_arg = matches.free.clone();
     if !free_arg.is_empty() {
         for arg in &free_arg {
             let ip = parse_address_mask(
                 arg,
-                input_mask,
-                input_mask,
+                v4_mask,
+                v6_mask,
                 input_base,
                 matches!(reverse, Reverse::Both | Reverse::Input),
                 &config,
@@ -589,6 +3008,50 @@ fn main() {
         }
     }
 
+    if matches.opt_present("allocate") || matches.opt_present("release") {
+        ledger(&matches, &ip_args, input_base, &reverse, &config);
+    }
+
+    if matches.opt_present("explain-format") {
+        let sample = match ip_args.first() {
+            Some(ip) => ip.clone(),
+            None => Ip {
+                address: Addr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1)),
+                cidr: 24,
+            },
+        };
+        explain_format(&sample, &matches, &rows, &config);
+        exit(0);
+    }
+
+    if matches.opt_present("query") {
+        query(&matches, &rows, csv_fields.as_deref(), &config);
+    }
+
+    if matches.opt_present("vlsm") {
+        vlsm_plan(&matches, &ip_args, &config);
+    }
+
+    if matches.opt_present("ula") {
+        ula(&matches, &rows, &config);
+    }
+
+    if matches.opt_present("assign") {
+        assign_hosts(&matches, &ip_args, input_base, &reverse, &config);
+    }
+
+    if !plan_levels.is_empty() {
+        plan_output(&matches, &ip_args, &plan_levels, &config);
+    }
+
+    if matches.opt_present("free") {
+        free_blocks(&matches, &ip_args, &rows, input_base, &reverse, &config);
+    }
+
+    if matches.opt_present("report") {
+        report(&matches, &rows, input_base, &reverse, &config);
+    }
+
     let stdin_ready = fd_ready(std::io::stdin().as_raw_fd());
     if (stdin_ready && wait_stdin(&matches)) || matches.opt_str("file").is_some() {
         let path = if stdin_ready {
@@ -597,11 +3060,11 @@ fn main() {
             matches.opt_str("file").unwrap()
         };
         process_input_file(
-            &path, &matches, input_base, &reverse, &ip_args, &rows, inside, &config,
+            &path, &matches, input_base, &reverse, &ip_args, &rows, &reference, inside, &config,
         );
 
         if ip_args.clone().is_empty() || inside.is_some() {
-            std::process::exit(0);
+            exit(0);
         }
     }
 
@@ -612,13 +3075,13 @@ fn main() {
             Addr::V4(_) => {
                 if arg.cidr > 32 {
                     eprintln!("V4 mask cannot be greater than 32");
-                    std::process::exit(1);
+                    exit(1);
                 }
             }
             Addr::V6(_) => {
                 if arg.cidr > 128 {
                     eprintln!("V6 mask cannot be greater than 128");
-                    std::process::exit(1);
+                    exit(1);
                 }
             }
         }
@@ -642,7 +3105,7 @@ fn main() {
                 }
                 None => {
                     eprintln!("Could not find an encapsulating network, sorry");
-                    std::process::exit(1);
+                    exit(1);
                 }
             }
         } else {
@@ -652,19 +3115,19 @@ fn main() {
                 }
                 None => {
                     eprintln!("Could not find an encapsulating network, sorry");
-                    std::process::exit(1);
+                    exit(1);
                 }
             }
         }
 
-        std::process::exit(0);
+        exit(0);
     }
 
     if ip_args.is_empty() {
-        println!("{}", opts.usage("ripcalc"));
+        outln!("{}", opts.usage("ripcalc"));
         eprintln!("Need to provide v4 or v6 address.");
-        std::process::exit(1);
+        exit(1);
     }
 
-    std::process::exit(0);
+    exit(0);
 }
@@ -1,8 +1,8 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::str::FromStr;
+use std::sync::Mutex;
 
 #[cfg(test)]
 mod test {
@@ -277,10 +277,7 @@ mod test {
             address: Addr::V4(Ipv4Addr::from_str("192.168.0.0").unwrap()),
             cidr: 30,
         };
-        let config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
 
         let f = format_details(&net, "%a".to_string(), &None, None, None, &config);
 
@@ -289,10 +286,7 @@ mod test {
 
     #[test]
     fn test_format_ng_percent() {
-        let config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
         let f = format_details(
             &Ip {
                 address: Addr::V4(Ipv4Addr::from_str("192.168.0.0").unwrap()),
@@ -357,10 +351,7 @@ mod test {
             cidr: 64,
         };
 
-        let config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
         let f = format_details(&net, "select * from IP6 where (ip >= %ln and ip <= %lb) and active = 1;\nupdate IP6 set active = 0 where (ip >= %ln and ip <= %lb) and active = 1;".to_string(), &None, None, None, &config);
 
         assert_eq!(f, Some("select * from IP6 where (ip >= 42540724579414763292693624807812497408 and ip <= 42540724579414763311140368881522049023) and active = 1;
@@ -374,10 +365,7 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
             cidr: 64,
         };
 
-        let config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
         let f = format_details(&net, "%%b".to_string(), &None, None, None, &config);
 
         assert_eq!(f, Some("%b".to_string()));
@@ -390,10 +378,7 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
             cidr: 64,
         };
 
-        let config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
         let f = format_details(&net, "%lb".to_string(), &None, None, None, &config);
 
         assert_eq!(
@@ -409,10 +394,7 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
             cidr: 64,
         };
 
-        let config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
         let f = format_details(&net, "%lb\n\n\n%%".to_string(), &None, None, None, &config);
 
         assert_eq!(
@@ -427,10 +409,7 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
             address: Addr::V6(Ipv6Addr::from_str("2001:ba8:1f1:f1cb::4").unwrap()),
             cidr: 64,
         };
-        let config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
 
         let f = format_details(&net, "\n".to_string(), &None, None, None, &config);
         assert_eq!(f, Some("\n".to_string()));
@@ -618,10 +597,7 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
 
     #[test]
     fn test_base_hex() {
-        let config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
         assert_eq!(
             parse_address_mask("192.168.1.1", None, None, Some(10), false, &config),
             Some(Ip {
@@ -655,10 +631,7 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
 
     #[test]
     fn test_reverse() {
-        let config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
 
         assert_eq!(
             parse_address_mask("0101A8C0", None, None, Some(16), true, &config),
@@ -676,14 +649,11 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
             cidr: 30,
         };
 
-        let mut config = RefCell::new(Config {
-            interface_names: vec![],
-            hm: HashMap::new(),
-        });
+        let config = Mutex::new(Config::default());
 
-        let f = format_details(&net, "%La".to_string(), &None, None, None, &mut config);
+        let f = format_details(&net, "%La".to_string(), &None, None, None, &config);
         assert_eq!(f, Some("-1819047474".to_string()));
-        let f = format_details(&net, "%la".to_string(), &None, None, None, &mut config);
+        let f = format_details(&net, "%la".to_string(), &None, None, None, &config);
         assert_eq!(f, Some("2475919822".to_string()));
 
         let net = Ip {
@@ -691,9 +661,9 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
             cidr: 30,
         };
 
-        let f = format_details(&net, "%La".to_string(), &None, None, None, &mut config);
+        let f = format_details(&net, "%La".to_string(), &None, None, None, &config);
         assert_eq!(f, Some("-5192296858534827628530496329154561".to_string()));
-        let f = format_details(&net, "%la".to_string(), &None, None, None, &mut config);
+        let f = format_details(&net, "%la".to_string(), &None, None, None, &config);
         assert_eq!(
             f,
             Some("340277174624079928635746076935439056895".to_string())
@@ -702,100 +672,79 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
 
     #[test]
     fn test_within_ipv4() {
-        assert_eq!(
-            within(
-                &Ip {
-                    address: Addr::V4(Ipv4Addr::from_str("127.0.0.1").unwrap()),
-                    cidr: 8
-                },
-                &Ip {
-                    address: Addr::V4(Ipv4Addr::from_str("10.0.0.0").unwrap()),
-                    cidr: 8
-                },
-            ),
-            false
-        );
-        assert_eq!(
-            within(
-                &Ip {
-                    address: Addr::V4(Ipv4Addr::from_str("192.168.0.0").unwrap()),
-                    cidr: 24
-                },
-                &Ip {
-                    address: Addr::V4(Ipv4Addr::from_str("192.168.0.0").unwrap()),
-                    cidr: 16
-                },
-            ),
-            false
-        );
-        assert_eq!(
-            within(
-                &Ip {
-                    address: Addr::V4(Ipv4Addr::from_str("127.0.0.1").unwrap()),
-                    cidr: 8
-                },
-                &Ip {
-                    address: Addr::V4(Ipv4Addr::from_str("192.168.0.0").unwrap()),
-                    cidr: 16
-                },
-            ),
-            false
-        );
-        assert_eq!(
-            within(
-                &Ip {
-                    address: Addr::V4(Ipv4Addr::from_str("127.0.0.1").unwrap()),
-                    cidr: 8
-                },
-                &Ip {
-                    address: Addr::V4(Ipv4Addr::from_str("127.1.1.1").unwrap()),
-                    cidr: 16
-                },
-            ),
-            true
-        );
+        assert!(!within(
+            &Ip {
+                address: Addr::V4(Ipv4Addr::from_str("127.0.0.1").unwrap()),
+                cidr: 8
+            },
+            &Ip {
+                address: Addr::V4(Ipv4Addr::from_str("10.0.0.0").unwrap()),
+                cidr: 8
+            },
+        ));
+        assert!(!within(
+            &Ip {
+                address: Addr::V4(Ipv4Addr::from_str("192.168.0.0").unwrap()),
+                cidr: 24
+            },
+            &Ip {
+                address: Addr::V4(Ipv4Addr::from_str("192.168.0.0").unwrap()),
+                cidr: 16
+            },
+        ));
+        assert!(!within(
+            &Ip {
+                address: Addr::V4(Ipv4Addr::from_str("127.0.0.1").unwrap()),
+                cidr: 8
+            },
+            &Ip {
+                address: Addr::V4(Ipv4Addr::from_str("192.168.0.0").unwrap()),
+                cidr: 16
+            },
+        ));
+        assert!(within(
+            &Ip {
+                address: Addr::V4(Ipv4Addr::from_str("127.0.0.1").unwrap()),
+                cidr: 8
+            },
+            &Ip {
+                address: Addr::V4(Ipv4Addr::from_str("127.1.1.1").unwrap()),
+                cidr: 16
+            },
+        ));
     }
     #[test]
     fn test_within_ipv6() {
-        assert_eq!(
-            within(
-                &Ip {
-                    address: Addr::V6(Ipv6Addr::from_str("::1").unwrap()),
-                    cidr: 48
-                },
-                &Ip {
-                    address: Addr::V6(Ipv6Addr::from_str("f::1").unwrap()),
-                    cidr: 48
-                },
-            ),
-            false
-        );
-        assert_eq!(
-            within(
-                &Ip {
-                    address: Addr::V6(Ipv6Addr::from_str("::1").unwrap()),
-                    cidr: 48
-                },
-                &Ip {
-                    address: Addr::V6(Ipv6Addr::from_str("dead:beef::cafe").unwrap()),
-                    cidr: 48
-                },
-            ),
-            false
-        );
-        assert_eq!(
-            within(
-                &Ip {
-                    address: Addr::V6(Ipv6Addr::from_str("1::1").unwrap()),
-                    cidr: 48
-                },
-                &Ip {
-                    address: Addr::V6(Ipv6Addr::from_str("::f:f:f:f:f").unwrap()),
-                    cidr: 48
-                },
-            ),
-            false
-        );
+        assert!(!within(
+            &Ip {
+                address: Addr::V6(Ipv6Addr::from_str("::1").unwrap()),
+                cidr: 48
+            },
+            &Ip {
+                address: Addr::V6(Ipv6Addr::from_str("f::1").unwrap()),
+                cidr: 48
+            },
+        ));
+        assert!(!within(
+            &Ip {
+                address: Addr::V6(Ipv6Addr::from_str("::1").unwrap()),
+                cidr: 48
+            },
+            &Ip {
+                address: Addr::V6(Ipv6Addr::from_str("dead:beef::cafe").unwrap()),
+                cidr: 48
+            },
+        ));
+        assert!(!within(
+            &Ip {
+                address: Addr::V6(Ipv6Addr::from_str("1::1").unwrap()),
+                cidr: 48
+            },
+            &Ip {
+                address: Addr::V6(Ipv6Addr::from_str("::f:f:f:f:f").unwrap()),
+                cidr: 48
+            },
+        ));
     }
 
     #[test]
@@ -874,6 +823,61 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
         assert_eq!(subnets_in_network(49, &net), 2);
     }
 
+    #[test]
+    fn test_count() {
+        let all = Ip {
+            address: Addr::V6(Ipv6Addr::from_str("::").unwrap()),
+            cidr: 0,
+        };
+        let size = network_size(&all);
+        assert_eq!(size, Count::pow2(128));
+        assert_eq!(size.to_string(), "340282366920938463463374607431768211456");
+        assert_eq!(size.to_u128(), None);
+        assert_eq!(size.power(), "2^128");
+        assert_eq!(size.scientific(), "3.4e38");
+
+        let two = size + size;
+        assert_eq!(two.to_string(), "680564733841876926926749214863536422912");
+        assert_eq!(two.power(), "2^129");
+        assert_eq!(
+            (size + Count::new(1)).to_string(),
+            "340282366920938463463374607431768211457"
+        );
+        assert_eq!(
+            (size + Count::new(1)).power(),
+            "340282366920938463463374607431768211457"
+        );
+        assert!(size + Count::new(1) > size);
+
+        assert_eq!(Count::pow2(64).scientific(), "1.8e19");
+        assert_eq!(Count::pow2(64).power(), "2^64");
+        assert_eq!(Count::new(9999).scientific(), "9999");
+        assert_eq!(Count::new(12).power(), "12");
+        assert_eq!(
+            vec![Count::new(u128::MAX), Count::new(1)]
+                .into_iter()
+                .sum::<Count>(),
+            size
+        );
+
+        assert_eq!(subnets_in_network(128, &all), size);
+        let net = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.0.0.0").unwrap()),
+            cidr: 24,
+        };
+        assert_eq!(subnets_in_network(16, &net), 1);
+
+        let config = Mutex::new(Config::default());
+        assert_eq!(
+            format_details(&all, "%t %Pt %Et".to_string(), &None, None, None, &config),
+            Some(format!("{} 2^128 3.4e38", size))
+        );
+        assert!(lint_format("%PN %Et", None, &config.lock().unwrap()).is_ok());
+        assert!(lint_format("%Pa", None, &config.lock().unwrap()).is_err());
+        assert!(lint_format("%xPt", None, &config.lock().unwrap()).is_err());
+        assert!(lint_format("%E", None, &config.lock().unwrap()).is_err());
+    }
+
     #[test]
     fn test_networks_sizing_v4() {
         let net = Ip {
@@ -916,7 +920,7 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
         assert_eq!(
             resp,
             [Ip {
-                address: Addr::V4(Ipv4Addr::from_str(&format!("192.168.0.0")).expect("bad ipv4")),
+                address: Addr::V4(Ipv4Addr::from_str("192.168.0.0").expect("bad ipv4")),
                 cidr: 22
             }]
         );
@@ -945,19 +949,19 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
             resp,
             [
                 Ip {
-                    address: Addr::V4(Ipv4Addr::from_str(&format!("192.0.0.0")).expect("bad ipv4")),
+                    address: Addr::V4(Ipv4Addr::from_str("192.0.0.0").expect("bad ipv4")),
                     cidr: 22
                 },
                 Ip {
-                    address: Addr::V4(Ipv4Addr::from_str(&format!("192.1.0.0")).expect("bad ipv4")),
+                    address: Addr::V4(Ipv4Addr::from_str("192.1.0.0").expect("bad ipv4")),
                     cidr: 22
                 },
                 Ip {
-                    address: Addr::V4(Ipv4Addr::from_str(&format!("192.2.0.0")).expect("bad ipv4")),
+                    address: Addr::V4(Ipv4Addr::from_str("192.2.0.0").expect("bad ipv4")),
                     cidr: 22
                 },
                 Ip {
-                    address: Addr::V4(Ipv4Addr::from_str(&format!("192.3.0.0")).expect("bad ipv4")),
+                    address: Addr::V4(Ipv4Addr::from_str("192.3.0.0").expect("bad ipv4")),
                     cidr: 22
                 },
             ]
@@ -987,22 +991,1054 @@ update IP6 set active = 0 where (ip >= 42540724579414763292693624807812497408 an
             resp,
             [
                 Ip {
-                    address: Addr::V4(Ipv4Addr::from_str(&format!("192.0.0.0")).expect("bad ipv4")),
+                    address: Addr::V4(Ipv4Addr::from_str("192.0.0.0").expect("bad ipv4")),
                     cidr: 24
                 },
                 Ip {
-                    address: Addr::V4(Ipv4Addr::from_str(&format!("192.0.1.0")).expect("bad ipv4")),
+                    address: Addr::V4(Ipv4Addr::from_str("192.0.1.0").expect("bad ipv4")),
                     cidr: 24
                 },
                 Ip {
-                    address: Addr::V4(Ipv4Addr::from_str(&format!("192.0.2.0")).expect("bad ipv4")),
+                    address: Addr::V4(Ipv4Addr::from_str("192.0.2.0").expect("bad ipv4")),
                     cidr: 24
                 },
                 Ip {
-                    address: Addr::V4(Ipv4Addr::from_str(&format!("192.0.3.0")).expect("bad ipv4")),
+                    address: Addr::V4(Ipv4Addr::from_str("192.0.3.0").expect("bad ipv4")),
                     cidr: 24
                 },
             ]
         );
     }
+
+    #[test]
+    fn test_format_lint() {
+        let config = Config::default();
+        assert_eq!(lint_format("%a/%c\n", None, &config), Ok(()));
+        assert_eq!(lint_format("%xa %Sb %%", None, &config), Ok(()));
+        assert!(lint_format("%q", None, &config).is_err());
+        assert!(lint_format("%Bc", None, &config).is_err());
+        assert!(lint_format("%{owner}", None, &config).is_err());
+
+        let fields = vec!["network".to_string(), "owner".to_string()];
+        assert_eq!(lint_format("%{owner}", Some(&fields), &config), Ok(()));
+        assert!(lint_format("%{ownr}", Some(&fields), &config).is_err());
+        assert_eq!(lint_format("%{owner@1}", Some(&fields), &config), Ok(()));
+        assert_eq!(lint_format("%{_prefix@2}", Some(&fields), &config), Ok(()));
+        assert!(lint_format("%{ownr@1}", Some(&fields), &config).is_err());
+    }
+
+    #[test]
+    fn test_match_levels() {
+        assert_eq!(field_level("owner@2"), ("owner", 2));
+        assert_eq!(field_level("owner"), ("owner", 0));
+        assert_eq!(field_level("a@b"), ("a@b", 0));
+
+        let mut rows = NetRows::default();
+        for (net, cidr, owner) in [
+            ("10.0.0.0", 16, "region"),
+            ("10.0.1.0", 24, "team"),
+            ("10.0.1.0", 28, "svc"),
+        ] {
+            let mut row = HashMap::new();
+            row.insert("owner".to_string(), owner.to_string());
+            rows.networks.insert(
+                Ip {
+                    address: Addr::V4(Ipv4Addr::from_str(net).unwrap()),
+                    cidr,
+                },
+                NetRow { row },
+            );
+        }
+        let config = Mutex::new(Config::default());
+        let ip = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.0.1.3").unwrap()),
+            cidr: 32,
+        };
+        let f = format_details(
+            &ip,
+            "%{owner} %{_prefix} %{owner@1} %{_prefix@2} %{owner@3}".to_string(),
+            &Some(rows),
+            None,
+            None,
+            &config,
+        );
+        assert_eq!(f, Some("svc 10.0.1.0/28 team 10.0.0.0/16 ".to_string()));
+    }
+
+    #[test]
+    fn test_format_tokens() {
+        assert_eq!(
+            format_tokens("ip %xa/%c %{owner}\\n"),
+            vec![
+                FormatToken::Literal("ip ".to_string()),
+                FormatToken::Placeholder {
+                    modifiers: "x".to_string(),
+                    key: 'a'
+                },
+                FormatToken::Literal("/".to_string()),
+                FormatToken::Placeholder {
+                    modifiers: "".to_string(),
+                    key: 'c'
+                },
+                FormatToken::Literal(" ".to_string()),
+                FormatToken::Field("owner".to_string()),
+                FormatToken::Escape('n'),
+            ]
+        );
+    }
+    #[derive(Debug)]
+    struct SitePlaceholder;
+
+    impl PlaceholderProvider for SitePlaceholder {
+        fn names(&self) -> Vec<String> {
+            vec!["site".to_string(), "x".to_string()]
+        }
+
+        fn render(&self, _name: &str, context: &mut PlaceholderContext) -> Option<String> {
+            match context.ip.address {
+                Addr::V4(x) => Some(format!("site{}", x.octets()[1])),
+                Addr::V6(_) => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_placeholder_provider() {
+        let net = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.4.0.1").unwrap()),
+            cidr: 24,
+        };
+        let mut config = Config::default();
+        config.register_placeholder(std::sync::Arc::new(SitePlaceholder));
+
+        assert_eq!(lint_format("%{site} %r", None, &config), Ok(()));
+        // a registered single character is only used as %{x}
+        assert_eq!(lint_format("%{x}", None, &config), Ok(()));
+        assert!(lint_format("%x", None, &config).is_err());
+
+        let config = Mutex::new(config);
+        let f = format_details(&net, "%{site} %r".to_string(), &None, None, None, &config);
+        assert_eq!(f, Some("site4 RFC 1918".to_string()));
+        let f = format_details(&net, "%{x}".to_string(), &None, None, None, &config);
+        assert_eq!(f, Some("site4".to_string()));
+
+        let net = Ip {
+            address: Addr::V6(Ipv6Addr::from_str("2001:db8::1").unwrap()),
+            cidr: 64,
+        };
+        let f = format_details(&net, "%{site}".to_string(), &None, None, None, &config);
+        assert_eq!(f, Some("{site}".to_string()));
+    }
+
+    #[test]
+    fn test_matching_rows() {
+        let mut rows = NetRows::default();
+        for (net, cidr, owner) in [("10.0.0.0", 8, "corp"), ("10.20.1.0", 24, "bob")] {
+            let mut row = HashMap::new();
+            row.insert("owner".to_string(), owner.to_string());
+            rows.networks.insert(
+                Ip {
+                    address: Addr::V4(Ipv4Addr::from_str(net).unwrap()),
+                    cidr,
+                },
+                NetRow { row },
+            );
+        }
+
+        let ip = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.20.1.1").unwrap()),
+            cidr: 32,
+        };
+        let m = matching_rows(&ip, &rows);
+        assert_eq!(m.len(), 2);
+        assert_eq!(m[0].network.as_ref().unwrap().cidr, 24);
+        assert_eq!(m[1].network.as_ref().unwrap().cidr, 8);
+
+        let ip = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.30.1.1").unwrap()),
+            cidr: 32,
+        };
+        let m = matching_rows(&ip, &rows);
+        assert_eq!(m.len(), 1);
+        assert_eq!(m[0].row.row.get("owner"), Some(&"corp".to_string()));
+    }
+
+    #[test]
+    fn test_matching_ranges() {
+        let mut rows = NetRows::default();
+        for (start, end, owner) in [
+            ("10.0.0.5", "10.0.3.200", "isp"),
+            ("10.0.1.7", "10.0.1.9", "cust"),
+            ("10.0.9.0", "10.0.9.9", "other"),
+        ] {
+            let mut row = HashMap::new();
+            row.insert("owner".to_string(), owner.to_string());
+            rows.ranges.push(NetRange {
+                start: Addr::V4(Ipv4Addr::from_str(start).unwrap()),
+                end: Addr::V4(Ipv4Addr::from_str(end).unwrap()),
+                row: NetRow { row },
+            });
+        }
+        rows.sort_ranges();
+
+        let ip = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.0.1.8").unwrap()),
+            cidr: 32,
+        };
+        let m = matching_rows(&ip, &rows);
+        assert_eq!(m.len(), 2);
+        assert_eq!(m[0].row.row.get("owner"), Some(&"cust".to_string()));
+        assert_eq!(m[1].row.row.get("owner"), Some(&"isp".to_string()));
+        assert!(m[0].network.is_none());
+
+        let ip = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.0.3.201").unwrap()),
+            cidr: 32,
+        };
+        assert!(matching_rows(&ip, &rows).is_empty());
+    }
+
+    #[test]
+    fn test_matching_unsorted_ranges() {
+        let mut rows = NetRows::default();
+        let mut row = HashMap::new();
+        row.insert("owner".to_string(), "isp".to_string());
+        rows.ranges.push(NetRange {
+            start: Addr::V4(Ipv4Addr::from_str("10.0.0.5").unwrap()),
+            end: Addr::V4(Ipv4Addr::from_str("10.0.3.200").unwrap()),
+            row: NetRow { row },
+        });
+
+        // ranges pushed without sort_ranges are still found
+        let ip = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.0.1.8").unwrap()),
+            cidr: 32,
+        };
+        let m = matching_rows(&ip, &rows);
+        assert_eq!(m.len(), 1);
+        assert_eq!(m[0].row.row.get("owner"), Some(&"isp".to_string()));
+    }
+
+    #[test]
+    fn test_range_networks() {
+        let a = |s: &str| Addr::V4(Ipv4Addr::from_str(s).unwrap());
+        let nets: Vec<String> = range_networks(&a("10.0.0.5"), &a("10.0.0.20"))
+            .iter()
+            .map(|n| format!("{}/{}", formatted_address(n, &FormatMode::Text), n.cidr))
+            .collect();
+        assert_eq!(
+            nets,
+            vec![
+                "10.0.0.5/32",
+                "10.0.0.6/31",
+                "10.0.0.8/29",
+                "10.0.0.16/30",
+                "10.0.0.20/32"
+            ]
+        );
+        assert_eq!(
+            range_networks(&a("0.0.0.0"), &a("255.255.255.255")).len(),
+            1
+        );
+
+        let v6 = |s: &str| Addr::V6(Ipv6Addr::from_str(s).unwrap());
+        let all = range_networks(&v6("::"), &v6("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].cidr, 0);
+
+        let merged = merge_ranges(vec![
+            (a("10.0.1.0"), a("10.0.1.255")),
+            (a("10.0.0.0"), a("10.0.0.255")),
+            (a("10.0.0.128"), a("10.0.0.130")),
+            (a("10.0.3.0"), a("10.0.3.0")),
+        ]);
+        assert_eq!(
+            merged,
+            vec![
+                (a("10.0.0.0"), a("10.0.1.255")),
+                (a("10.0.3.0"), a("10.0.3.0"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_free_ranges() {
+        let a = |s: &str| Addr::V4(Ipv4Addr::from_str(s).unwrap());
+        let net = Ip {
+            address: a("10.20.0.0"),
+            cidr: 16,
+        };
+        let free = free_ranges(
+            &net,
+            vec![
+                (a("10.20.2.0"), a("10.20.3.255")),
+                (a("10.20.0.0"), a("10.20.0.255")),
+                (a("10.19.0.0"), a("10.20.0.10")),
+                (a("10.20.255.0"), a("10.21.0.255")),
+            ],
+        );
+        assert_eq!(
+            free,
+            vec![
+                (a("10.20.1.0"), a("10.20.1.255")),
+                (a("10.20.4.0"), a("10.20.254.255"))
+            ]
+        );
+
+        assert_eq!(
+            free_ranges(&net, vec![(a("10.0.0.0"), a("10.255.255.255"))]),
+            vec![]
+        );
+        assert_eq!(
+            free_ranges(&net, vec![]),
+            vec![(a("10.20.0.0"), a("10.20.255.255"))]
+        );
+    }
+
+    #[test]
+    fn test_vlsm() {
+        let a = |s: &str| Addr::V4(Ipv4Addr::from_str(s).unwrap());
+        assert_eq!(cidr_for_hosts(&a("10.0.0.0"), 500), Some(23));
+        assert_eq!(cidr_for_hosts(&a("10.0.0.0"), 510), Some(23));
+        assert_eq!(cidr_for_hosts(&a("10.0.0.0"), 511), Some(22));
+        assert_eq!(cidr_for_hosts(&a("10.0.0.0"), 2), Some(30));
+        assert_eq!(cidr_for_hosts(&a("10.0.0.0"), 0), Some(31));
+        assert_eq!(cidr_for_hosts(&a("10.0.0.0"), 1 << 32), None);
+        let v6 = Addr::V6(Ipv6Addr::from_str("fd00::").unwrap());
+        assert_eq!(cidr_for_hosts(&v6, 1 << 64), Some(64));
+
+        let net = Ip {
+            address: a("10.0.0.0"),
+            cidr: 22,
+        };
+        let needs: Vec<(String, u128)> = [("p2p", 2), ("office", 500), ("voip", 120)]
+            .iter()
+            .map(|(n, h)| (n.to_string(), *h))
+            .collect();
+        let plan = vlsm(&net, &needs).unwrap();
+        let plan: Vec<(String, u128, String)> = plan
+            .into_iter()
+            .map(|(n, h, ip)| (n, h, format!("{}/{}", ip, ip.cidr)))
+            .collect();
+        assert_eq!(
+            plan,
+            vec![
+                ("office".to_string(), 500, "10.0.0.0/23".to_string()),
+                ("voip".to_string(), 120, "10.0.2.0/25".to_string()),
+                ("p2p".to_string(), 2, "10.0.2.128/30".to_string()),
+            ]
+        );
+
+        let net = Ip {
+            address: a("10.0.0.0"),
+            cidr: 23,
+        };
+        assert!(vlsm(&net, &needs).is_err());
+    }
+
+    #[test]
+    fn test_plan() {
+        assert_eq!(parse_count("2^40"), Some(1 << 40));
+        assert_eq!(parse_count("0x100"), Some(256));
+        assert_eq!(parse_count("12"), Some(12));
+        assert_eq!(parse_count("x"), None);
+
+        assert!(parse_plan("site").is_err());
+        assert!(parse_plan("site:0").is_err());
+        assert!(parse_plan("vlan:8=256").is_err());
+        assert!(parse_plan("vlan:8=5-4").is_err());
+
+        let levels = parse_plan("region:4 site:12 vlan:16=0x10,0x100-0x101").unwrap();
+        assert_eq!(levels[2].count(), 3);
+        assert_eq!(levels[2].nth(2), 0x101);
+        assert_eq!(levels[2].number(0x101), "101");
+        assert_eq!(levels[1].number(10), "10");
+
+        let net = Ip {
+            address: Addr::V6(Ipv6Addr::from_str("2001:db8::").unwrap()),
+            cidr: 32,
+        };
+        let plan = Plan::new(&net, levels).unwrap();
+        assert_eq!(plan.len, 16 * 4096 * 3);
+
+        let (numbers, ip) = plan.get(4096 * 3 + 3 + 2).unwrap();
+        assert_eq!(numbers, vec![1, 1, 0x101]);
+        assert_eq!(ip.to_string(), "2001:db8:1001:101::");
+        assert_eq!(ip.cidr, 64);
+        assert!(plan.get(plan.len).is_none());
+        assert_eq!(plan.range(plan.len - 1, u128::MAX).count(), 1);
+        assert_eq!(
+            plan.range(5, 7).map(|(n, _)| n[2]).collect::<Vec<_>>(),
+            vec![0x101, 0x10]
+        );
+
+        let net = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.0.0.0").unwrap()),
+            cidr: 24,
+        };
+        assert!(Plan::new(&net, parse_plan("a:8 b:1").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_ula() {
+        assert_eq!(
+            eui64([0x00, 0x0c, 0x29, 0x01, 0x02, 0x03]),
+            0x020c29fffe010203
+        );
+
+        // ntp seconds are from 1900
+        let key = ula_key(std::time::Duration::new(0, 500_000_000), 1);
+        assert_eq!(key[..4], 2_208_988_800u32.to_be_bytes());
+        assert_eq!(key[4..8], 0x8000_0000u32.to_be_bytes());
+        assert_eq!(key[8..], 1u64.to_be_bytes());
+
+        let ula = ula_prefix(b"lab");
+        assert_eq!(ula.cidr, 48);
+        assert_eq!(ula.to_string(), "fd5b:4411:69bf::");
+        assert_eq!(ula_prefix(b"lab"), ula);
+        assert_ne!(ula_prefix(b"lab2"), ula);
+
+        let used = Addr::V6(Ipv6Addr::from_str("fd5b:4411:69bf:1::1").unwrap());
+        assert!(net_in_use(&ula, &None, &[used]));
+        assert!(!net_in_use(&ula, &None, &[]));
+
+        let mut rows = NetRows::default();
+        rows.networks.insert(
+            Ip {
+                address: Addr::V6(Ipv6Addr::from_str("fd5b::").unwrap()),
+                cidr: 16,
+            },
+            NetRow {
+                row: HashMap::new(),
+            },
+        );
+        assert!(net_in_use(&ula, &Some(rows), &[]));
+    }
+
+    #[test]
+    fn test_walk_addresses() {
+        let net = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.0.0.0").unwrap()),
+            cidr: 24,
+        };
+        let list = |mask, walk: &Walk| -> Vec<String> {
+            walk_addresses(&net, None, mask, walk)
+                .map(|ip| format!("{}/{}", ip, ip.cidr))
+                .collect()
+        };
+
+        assert_eq!(list(None, &Walk::default()).len(), 256);
+        assert_eq!(
+            list(
+                None,
+                &Walk {
+                    offset: 250,
+                    limit: Some(2),
+                    ..Walk::default()
+                }
+            ),
+            vec!["10.0.0.250/24", "10.0.0.251/24"]
+        );
+        assert_eq!(
+            list(
+                Some(26),
+                &Walk {
+                    offset: 1,
+                    step: 2,
+                    descending: true,
+                    ..Walk::default()
+                }
+            ),
+            vec!["10.0.0.128/26", "10.0.0.0/26"]
+        );
+        assert!(list(
+            None,
+            &Walk {
+                offset: 256,
+                ..Walk::default()
+            }
+        )
+        .is_empty());
+
+        // used ranges are jumped over, keeping to the steps
+        let a = |s: &str| Addr::V4(Ipv4Addr::from_str(s).unwrap());
+        let used = merge_ranges(vec![
+            (a("10.0.0.1"), a("10.0.0.1")),
+            (a("10.0.0.4"), a("10.0.0.7")),
+            (a("10.0.0.200"), a("10.0.1.255")),
+        ]);
+        let free = |mask, walk: &Walk| -> Vec<String> {
+            walk_addresses(&net, Some(&used), mask, walk)
+                .map(|ip| ip.to_string())
+                .collect()
+        };
+        let walk = Walk {
+            limit: Some(4),
+            ..Walk::default()
+        };
+        assert_eq!(
+            free(None, &walk),
+            vec!["10.0.0.0", "10.0.0.2", "10.0.0.3", "10.0.0.8"]
+        );
+        let walk = Walk { step: 3, ..walk };
+        assert_eq!(
+            free(None, &walk),
+            vec!["10.0.0.0", "10.0.0.3", "10.0.0.9", "10.0.0.12"]
+        );
+        let walk = Walk {
+            descending: true,
+            ..walk
+        };
+        assert_eq!(
+            free(None, &walk),
+            vec!["10.0.0.198", "10.0.0.195", "10.0.0.192", "10.0.0.189"]
+        );
+        assert_eq!(
+            free(Some(26), &Walk::default()),
+            vec!["10.0.0.64", "10.0.0.128"]
+        );
+        assert_eq!(free(None, &Walk::default()).len(), 256 - 1 - 4 - 56);
+
+        // seeking is arithmetic, a /64 is not walked
+        let v6 = Ip {
+            address: Addr::V6(Ipv6Addr::from_str("2001:db8::").unwrap()),
+            cidr: 64,
+        };
+        let walk = Walk {
+            offset: 1 << 40,
+            limit: Some(1),
+            ..Walk::default()
+        };
+        let ip = walk_addresses(&v6, None, None, &walk).next().unwrap();
+        assert_eq!(ip.to_string(), "2001:db8::100:0:0");
+        let walk = Walk {
+            descending: true,
+            ..walk
+        };
+        let ip = walk_addresses(&v6, None, None, &walk).next().unwrap();
+        assert_eq!(ip.to_string(), "2001:db8::ffff:feff:ffff:ffff");
+    }
+
+    #[test]
+    fn test_host_index() {
+        let net = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("10.0.1.7").unwrap()),
+            cidr: 24,
+        };
+        let at = |i| host_index(&net, i).map(|ip| ip.to_string());
+        assert_eq!(at(0), Some("10.0.1.0".to_string()));
+        assert_eq!(at(1), Some("10.0.1.1".to_string()));
+        assert_eq!(at(255), Some("10.0.1.255".to_string()));
+        assert_eq!(at(-1), Some("10.0.1.255".to_string()));
+        assert_eq!(at(-256), Some("10.0.1.0".to_string()));
+        assert_eq!(at(256), None);
+        assert_eq!(at(-257), None);
+        assert_eq!(host_index(&net, 1).unwrap().cidr, 24);
+
+        let all = Ip {
+            address: Addr::V6(Ipv6Addr::from_str("::").unwrap()),
+            cidr: 0,
+        };
+        assert_eq!(
+            host_index(&all, -1).unwrap().to_string(),
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+        );
+
+        let config = Mutex::new(Config::default());
+        let host = host_index(&net, 10).unwrap();
+        let f = |config| format_details(&host, "%i %o\n".to_string(), &None, None, None, config);
+        assert_eq!(f(&config), Some("0 10\n".to_string()));
+        assert_eq!(f(&config), Some("1 10\n".to_string()));
+        assert!(lint_format("%o %i", None, &config.lock().unwrap()).is_ok());
+        assert!(lint_format("%xo", None, &config.lock().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_assign() {
+        let a = |s: &str| Addr::V4(Ipv4Addr::from_str(s).unwrap());
+        let net = |s: &str, cidr| Ip {
+            address: match std::net::IpAddr::from_str(s).unwrap() {
+                std::net::IpAddr::V4(x) => Addr::V4(x),
+                std::net::IpAddr::V6(x) => Addr::V6(x),
+            },
+            cidr,
+        };
+
+        assert_eq!(host_bounds(&net("10.0.0.0", 24)), (1, 254));
+        assert_eq!(host_bounds(&net("10.0.0.0", 31)), (0, 1));
+        assert_eq!(host_bounds(&net("10.0.0.1", 32)), (0, 0));
+        assert_eq!(
+            host_bounds(&net("2001:db8::", 64)),
+            (1, u64::MAX as u128 - 128)
+        );
+        assert_eq!(host_bounds(&net("2001:db8::", 127)), (0, 1));
+        assert_eq!(host_bounds(&net("::", 0)).1, u128::MAX - 128);
+
+        let lab = net("10.40.0.0", 22);
+        let ip = assign("web-17", &lab, &[]).unwrap();
+        assert_eq!(ip.address, a("10.40.1.204"));
+        assert_eq!(ip.cidr, 22);
+        assert_eq!(assign("web-17", &lab, &[]), Some(ip));
+
+        // collisions move up, past used networks and round to the start
+        let used = vec![(a("10.40.1.204"), a("10.40.1.205"))];
+        assert_eq!(
+            assign("web-17", &lab, &used).unwrap().address,
+            a("10.40.1.206")
+        );
+        let used = vec![(a("10.40.1.0"), a("10.40.3.255"))];
+        assert_eq!(
+            assign("web-17", &lab, &used).unwrap().address,
+            a("10.40.0.1")
+        );
+        let used = vec![(a("10.40.0.0"), a("10.40.3.255"))];
+        assert!(assign("web-17", &lab, &used).is_none());
+
+        let ip = assign("a", &net("10.0.0.0", 30), &[]).unwrap();
+        assert!(ip.address == a("10.0.0.1") || ip.address == a("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_diff_networks() {
+        let entry = |net: &str, cidr: u32, owner: &str| {
+            let ip = Ip {
+                address: Addr::V4(Ipv4Addr::from_str(net).unwrap()),
+                cidr,
+            };
+            let mut row = HashMap::new();
+            row.insert("owner".to_string(), owner.to_string());
+            NetRange {
+                start: network(&ip).address,
+                end: broadcast(&ip).address,
+                row: NetRow { row },
+            }
+        };
+        let old = vec![
+            entry("10.0.0.0", 16, "corp"),
+            entry("10.1.0.0", 24, "bob"),
+            entry("10.2.0.0", 23, "x"),
+            entry("10.4.0.0", 24, "dave"),
+            entry("10.5.0.0", 24, "m"),
+            entry("10.5.1.0", 24, "m"),
+        ];
+        let new = vec![
+            entry("10.0.0.0", 16, "corp"),
+            entry("10.1.0.0", 24, "eve"),
+            entry("10.2.0.0", 24, "x"),
+            entry("10.2.1.0", 24, "y"),
+            entry("10.3.0.0", 24, "carol"),
+            entry("10.5.0.0", 23, "m"),
+            entry("10.0.9.0", 24, "new"),
+        ];
+
+        assert_eq!(
+            diff_networks(&old, &new),
+            vec![
+                NetChange::Added(6),
+                NetChange::Changed(1, 1),
+                NetChange::Split(2, vec![2, 3]),
+                NetChange::Added(4),
+                NetChange::Removed(3),
+                NetChange::Merged(vec![4, 5], 5),
+            ]
+        );
+        assert_eq!(diff_networks(&old, &old), vec![]);
+    }
+
+    #[test]
+    fn test_row_usage() {
+        let ip = |s: &str, cidr: u32| Ip {
+            address: Addr::V4(Ipv4Addr::from_str(s).unwrap()),
+            cidr,
+        };
+        let mut u = RowUsage::default();
+        assert_eq!(u.distinct(), 0);
+
+        u.add(&ip("10.0.1.1", 32));
+        u.add(&ip("10.0.1.2", 32));
+        u.add(&ip("10.0.1.1", 32));
+        u.add(&ip("10.0.1.0", 30));
+        assert_eq!(u.hits, 4);
+        assert_eq!(u.distinct(), 4);
+        assert_eq!(u.first, Some(ip("10.0.1.1", 32)));
+        assert_eq!(u.last, Some(ip("10.0.1.0", 30)));
+    }
+
+    #[test]
+    fn test_allocate() {
+        let a = |s: &str| Addr::V4(Ipv4Addr::from_str(s).unwrap());
+        let pool = Ip {
+            address: a("10.0.0.0"),
+            cidr: 24,
+        };
+        let used = vec![
+            (a("10.0.0.0"), a("10.0.0.15")),
+            (a("10.0.0.64"), a("10.0.0.95")),
+            (a("10.0.0.128"), a("10.0.0.159")),
+        ];
+
+        let ip = allocate(&pool, used.clone(), 28, None, &Fit::First).unwrap();
+        assert_eq!(ip.address, a("10.0.0.16"));
+        assert_eq!(ip.cidr, 28);
+
+        // 10.0.0.96-127 is the smallest free space that a /27 fits
+        let ip = allocate(&pool, used.clone(), 27, None, &Fit::First).unwrap();
+        assert_eq!(ip.address, a("10.0.0.32"));
+        let ip = allocate(&pool, used.clone(), 27, None, &Fit::Best).unwrap();
+        assert_eq!(ip.address, a("10.0.0.96"));
+
+        let ip = allocate(&pool, used.clone(), 28, Some(26), &Fit::First).unwrap();
+        assert_eq!(ip.address, a("10.0.0.192"));
+
+        assert!(allocate(&pool, used.clone(), 26, None, &Fit::First).is_some());
+        assert!(allocate(&pool, used, 25, None, &Fit::First).is_none());
+        assert!(allocate(&pool, vec![], 23, None, &Fit::First).is_none());
+    }
+
+    #[test]
+    fn test_query_rows() {
+        let mut rows = NetRows::default();
+        for (net, owner, site) in [
+            ("10.0.1.0", "bob", "lon2"),
+            ("10.0.0.0", "bob", "lon1"),
+            ("10.0.3.0", "bob", "nyc"),
+            ("10.0.2.0", "alice", "lon3"),
+        ] {
+            let mut row = HashMap::new();
+            row.insert("owner".to_string(), owner.to_string());
+            row.insert("site".to_string(), site.to_string());
+            rows.networks.insert(
+                Ip {
+                    address: Addr::V4(Ipv4Addr::from_str(net).unwrap()),
+                    cidr: 24,
+                },
+                NetRow { row },
+            );
+        }
+
+        let p = vec![
+            RowPredicate::parse("owner=bob").unwrap(),
+            RowPredicate::parse("site~^lon").unwrap(),
+        ];
+        let found = query_rows(&rows, &p);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].row.row.get("site"), Some(&"lon1".to_string()));
+        assert_eq!(found[1].row.row.get("site"), Some(&"lon2".to_string()));
+
+        let p = vec![RowPredicate::parse("owner!=bob").unwrap()];
+        assert_eq!(query_rows(&rows, &p).len(), 1);
+        assert!(RowPredicate::parse("owner").is_err());
+        assert!(RowPredicate::parse("owner~(").is_err());
+
+        let config = Mutex::new(Config::default());
+        assert_eq!(
+            format_row(&found[0], "%{_prefix} %{site} %t", None, &config),
+            "10.0.0.0/24 lon1 256"
+        );
+    }
+
+    #[test]
+    fn test_filter_rows() {
+        let mut rows = NetRows::default();
+        for (net, cidr, role) in [("10.0.0.0", 8, "core"), ("104.16.0.0", 13, "edge")] {
+            let mut row = HashMap::new();
+            row.insert("role".to_string(), role.to_string());
+            rows.networks.insert(
+                Ip {
+                    address: Addr::V4(Ipv4Addr::from_str(net).unwrap()),
+                    cidr,
+                },
+                NetRow { row },
+            );
+        }
+
+        let edge = filter_rows(&rows, &[RowPredicate::parse("role=edge").unwrap()]);
+        assert_eq!(edge.networks.len(), 1);
+
+        let ip = |s: &str, cidr: u32| Ip {
+            address: Addr::V4(Ipv4Addr::from_str(s).unwrap()),
+            cidr,
+        };
+        assert!(row_within(&edge, &ip("104.16.1.1", 32)).is_some());
+        assert!(row_within(&edge, &ip("10.1.1.1", 32)).is_none());
+        assert!(row_within(&rows, &ip("10.1.1.0", 24)).is_some());
+        assert!(row_within(&rows, &ip("10.0.0.0", 7)).is_none());
+    }
+
+    #[test]
+    fn test_range_network() {
+        let a = |s: &str| Addr::V4(Ipv4Addr::from_str(s).unwrap());
+        assert_eq!(
+            range_network(&a("10.0.0.0"), &a("10.0.0.255")),
+            Some(Ip {
+                address: a("10.0.0.0"),
+                cidr: 24
+            })
+        );
+        assert_eq!(range_network(&a("10.0.0.1"), &a("10.0.0.255")), None);
+        assert_eq!(range_network(&a("10.0.0.0"), &a("10.0.0.254")), None);
+        assert_eq!(
+            range_network(&a("0.0.0.0"), &a("255.255.255.255")),
+            Some(Ip {
+                address: a("0.0.0.0"),
+                cidr: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_text() {
+        assert_eq!(decode_text(b"caf\xe9", "latin1"), Ok("café".to_string()));
+        assert_eq!(
+            decode_text(b"\x93ok\x94", "windows-1252"),
+            Ok("\u{201c}ok\u{201d}".to_string())
+        );
+        assert_eq!(
+            decode_text(b"\xff\xfea\x00b\x00", "utf-16"),
+            Ok("ab".to_string())
+        );
+        assert_eq!(decode_text(b"\x00a\x00b", "UTF-16BE"), Ok("ab".to_string()));
+        assert!(decode_text(b"caf\xe9", "utf-8").is_err());
+        assert!(decode_text(b"a", "ebcdic").is_err());
+    }
+
+    #[test]
+    fn test_encode_text() {
+        assert_eq!(encode_text("café", "latin1", b""), Ok(b"caf\xe9".to_vec()));
+        assert_eq!(
+            encode_text("\u{201c}ok\u{201d}", "windows-1252", b""),
+            Ok(b"\x93ok\x94".to_vec())
+        );
+        assert_eq!(
+            encode_text("ab", "utf-16", b"\xff\xfe"),
+            Ok(b"\xff\xfea\x00b\x00".to_vec())
+        );
+        assert_eq!(
+            encode_text("ab", "utf-16", b"\xfe\xff"),
+            Ok(b"\xfe\xff\x00a\x00b".to_vec())
+        );
+        assert_eq!(
+            encode_text("ab", "UTF-16BE", b""),
+            Ok(b"\x00a\x00b".to_vec())
+        );
+        assert!(encode_text("\u{201c}", "latin1", b"").is_err());
+        assert!(encode_text("\u{80}", "windows-1252", b"").is_err());
+        assert!(encode_text("a", "ebcdic", b"").is_err());
+    }
+
+    #[test]
+    fn test_line_ips() {
+        let config = Mutex::new(Config::default());
+        let ips = line_ips(
+            " 10.0.0.1  2001:db8::1/48 nope ",
+            None,
+            &Reverse::None,
+            &config,
+        );
+        assert_eq!(
+            ips.iter()
+                .map(|x| format!("{}/{}", x, x.cidr))
+                .collect::<Vec<String>>(),
+            vec!["10.0.0.1/32", "2001:db8::1/48"]
+        );
+
+        let mut reader: Box<dyn std::io::BufRead> = Box::new(&b"10.0.0.1\r\n\xff\n10.0.0.2"[..]);
+        assert_eq!(input_line(&mut reader), Some("10.0.0.1".to_string()));
+        assert_eq!(input_line(&mut reader), Some("10.0.0.2".to_string()));
+        assert_eq!(input_line(&mut reader), None);
+
+        // config is shared by --threads
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let ip = &line_ips("192.168.1.1/24", None, &Reverse::None, &config)[0];
+                    assert_eq!(ip.cidr, 24);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_csv_index() {
+        let mut rows = NetRows::default();
+        for (net, cidr, owner) in [
+            ("10.0.0.0", 8, "corp"),
+            ("10.20.1.0", 24, "bob"),
+            ("2001:db8::", 32, "doc"),
+        ] {
+            let mut row = HashMap::new();
+            row.insert("owner".to_string(), owner.to_string());
+            let address = match std::net::IpAddr::from_str(net).unwrap() {
+                std::net::IpAddr::V4(x) => Addr::V4(x),
+                std::net::IpAddr::V6(x) => Addr::V6(x),
+            };
+            rows.networks.insert(Ip { address, cidr }, NetRow { row });
+        }
+        let mut row = HashMap::new();
+        row.insert("owner".to_string(), "cust".to_string());
+        rows.ranges.push(NetRange {
+            start: Addr::V4(Ipv4Addr::from_str("10.20.1.7").unwrap()),
+            end: Addr::V4(Ipv4Addr::from_str("10.20.1.9").unwrap()),
+            row: NetRow { row },
+        });
+        rows.sort_ranges();
+
+        let dir = std::env::temp_dir();
+        let csv = dir.join(format!("ripcalc-index-{}.csv", std::process::id()));
+        let idx = dir.join(format!("ripcalc-index-{}.idx", std::process::id()));
+        let (csv, idx) = (csv.to_str().unwrap(), idx.to_str().unwrap());
+        std::fs::write(csv, "network,owner\n").unwrap();
+
+        let sources = vec![IndexSource::read(csv, true).unwrap()];
+        let fields = vec!["network".to_string(), "owner".to_string()];
+        std::fs::write(idx, compile_index(&rows, &fields, &sources, "key")).unwrap();
+
+        let index = CsvIndex::open(idx).unwrap();
+        assert_eq!(index.fields, fields);
+        assert_eq!(index.stale("key"), None);
+        assert!(index.stale("other").is_some());
+        let mut indexed = NetRows::from(index);
+
+        let owners = |rows: &NetRows, a: &str| {
+            let address = match std::net::IpAddr::from_str(a).unwrap() {
+                std::net::IpAddr::V4(x) => Addr::V4(x),
+                std::net::IpAddr::V6(x) => Addr::V6(x),
+            };
+            matching_rows(&Ip { address, cidr: 32 }, rows)
+                .iter()
+                .map(|m| format!("{} {}", m.prefix(), m.row.row["owner"]))
+                .collect::<Vec<String>>()
+        };
+        for a in [
+            "10.20.1.8",
+            "10.20.1.20",
+            "10.9.9.9",
+            "11.0.0.1",
+            "2001:db8::1",
+        ] {
+            assert_eq!(owners(&indexed, a), owners(&rows, a));
+        }
+        assert_eq!(
+            owners(&indexed, "10.20.1.8"),
+            vec![
+                "10.20.1.7-10.20.1.9 cust",
+                "10.20.1.0/24 bob",
+                "10.0.0.0/8 corp"
+            ]
+        );
+
+        indexed.load_index();
+        assert_eq!(indexed.networks.len(), 3);
+        assert_eq!(indexed.ranges.len(), 1);
+        assert_eq!(owners(&indexed, "10.20.1.8"), owners(&rows, "10.20.1.8"));
+
+        std::fs::write(csv, "network,owner\n10.0.0.0/8,corp\n").unwrap();
+        let index = CsvIndex::open(idx).unwrap();
+        assert!(index.stale("key").unwrap().contains("has changed"));
+        assert!(CsvIndex::open(csv).is_err());
+
+        // a damaged row is refused when the index is opened, not read as blank
+        let good = compile_index(&rows, &fields, &sources, "key");
+        let at = good.windows(4).position(|w| w == b"corp").unwrap();
+        let mut damaged = good.clone();
+        damaged[at + 1] = 0xff;
+        std::fs::write(idx, damaged).unwrap();
+        assert!(CsvIndex::open(idx).is_err());
+
+        // a damaged index is refused or still read without panicking
+        for i in 0..good.len() {
+            for damaged in [good[..i].to_vec(), {
+                let mut x = good.clone();
+                x[i] ^= 0xff;
+                x
+            }] {
+                std::fs::write(idx, damaged).unwrap();
+                if let Ok(index) = CsvIndex::open(idx) {
+                    let mut indexed = NetRows::from(index);
+                    for address in [
+                        Addr::V4(Ipv4Addr::from_str("10.20.1.8").unwrap()),
+                        Addr::V6(Ipv6Addr::from_str("2001:db8::1").unwrap()),
+                    ] {
+                        matching_rows(&Ip { address, cidr: 32 }, &indexed);
+                    }
+                    indexed.load_index();
+                }
+            }
+        }
+
+        std::fs::remove_file(csv).unwrap();
+        std::fs::remove_file(idx).unwrap();
+    }
+
+    #[test]
+    fn test_row_sources() {
+        let row = NetRow {
+            row: [
+                ("owner", "local"),
+                ("_source", "c"),
+                ("_sources", "c b a"),
+                ("b.owner", "site"),
+                ("b.note", "b"),
+                ("a.owner", "corp"),
+                ("a.note", "a"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        };
+        assert_eq!(row.sources(), vec!["c", "b", "a"]);
+        assert_eq!(row.get("owner"), Some(&"local".to_string()));
+        assert_eq!(row.get("c.owner"), Some(&"local".to_string()));
+        assert_eq!(row.get("note"), Some(&"b".to_string()));
+        assert_eq!(row.get("a.note"), Some(&"a".to_string()));
+        assert_eq!(row.get("c.note"), None);
+        assert_eq!(row.get("d.owner"), None);
+    }
+
+    #[test]
+    fn test_ptr_cache() {
+        let ip = |a: &str| Ip {
+            address: Addr::V4(Ipv4Addr::from_str(a).unwrap()),
+            cidr: 32,
+        };
+        let hm = LookupCache::default();
+        hm.insert("p/192.0.2.1".to_string(), "one.example".to_string());
+        hm.insert("p/192.0.2.2".to_string(), "two.example".to_string());
+
+        assert_eq!(ptr_format(&ip("192.0.2.1"), &hm), "one.example");
+        assert_eq!(ptr_format(&ip("192.0.2.2"), &hm), "two.example");
+    }
+
+    static UNLOCKED: std::sync::LazyLock<Mutex<Config>> =
+        std::sync::LazyLock::new(|| Mutex::new(Config::default()));
+
+    #[derive(Debug)]
+    struct UnlockedPlaceholder;
+
+    impl PlaceholderProvider for UnlockedPlaceholder {
+        fn names(&self) -> Vec<String> {
+            vec!["unlocked".to_string()]
+        }
+
+        fn render(&self, _name: &str, _context: &mut PlaceholderContext) -> Option<String> {
+            Some(UNLOCKED.try_lock().is_ok().to_string())
+        }
+    }
+
+    #[test]
+    fn test_placeholder_unlocked() {
+        let ip = Ip {
+            address: Addr::V4(Ipv4Addr::from_str("192.0.2.1").unwrap()),
+            cidr: 32,
+        };
+        UNLOCKED
+            .lock()
+            .unwrap()
+            .register_placeholder(std::sync::Arc::new(UnlockedPlaceholder));
+
+        assert_eq!(
+            render_placeholder("unlocked", &ip, None, &UNLOCKED),
+            Some("true".to_string())
+        );
+    }
 }